@@ -1,33 +1,382 @@
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
 use strum::EnumString;
 use thiserror::Error;
+use tracing::warn;
 
-use crate::random::random_base64;
+use crate::random::{random_base64, random_hex};
 
+/// Path of the garage binary in the image.
+pub const DEFAULT_GARAGE_BINARY: &str = "/garage";
+const DEFAULT_CONFIG_PATH: &str = "/etc/garage.toml";
+const DEFAULT_METADATA_DIR: &str = "/var/lib/garage/meta";
+const DEFAULT_DATA_DIR: &str = "/var/lib/garage/data";
+const DEFAULT_START_TIMEOUT_SECS: u64 = 20;
+const DEFAULT_START_POLL_INTERVAL_MS: u64 = 100;
+const DEFAULT_START_LOG_INTERVAL_SECS: u64 = 1;
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+const DEFAULT_ADMIN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_API_RETRIES: u64 = 3;
+const DEFAULT_BUCKET_CONCURRENCY: u64 = 4;
+const DEFAULT_MAX_RESTARTS: u64 = 3;
+const DEFAULT_RESTART_BACKOFF_SECS: u64 = 1;
+const DEFAULT_TOKEN_BYTES: u64 = 32;
+const MIN_TOKEN_BYTES: u64 = 16;
+const DEFAULT_ZONE: &str = "dc1";
+/// Range of `block_size` accepted for garage's data blocks.
+const BLOCK_SIZE_RANGE: std::ops::RangeInclusive<u64> = (1 << 10)..=(1 << 30);
+const DEFAULT_NODE_CAPACITY: u64 = i64::MAX as u64;
+const DEFAULT_REPLICATION_FACTOR: u64 = 1;
+/// `GARAGE_METRICS_TOKEN` value that omits the metrics token from the config.
+const METRICS_TOKEN_DISABLED: &str = "disabled";
+const CORS_METHODS: &[&str] = &["GET", "PUT", "POST", "DELETE", "HEAD"];
+
+#[derive(Debug)]
 pub struct Config {
-    pub admin_token: String,
-    pub metrics_token: String,
+    pub garage_binary: PathBuf,
+    /// Arguments appended to `garage -c <config> server`.
+    pub extra_args: Vec<String>,
+    pub config_path: PathBuf,
+    pub config_mode: ConfigMode,
+    pub config_extra_path: Option<PathBuf>,
+    pub metadata_dir: PathBuf,
+    pub data_dir: PathBuf,
+    pub db_engine: DbEngine,
+    /// `None` keeps the compression level of the template or existing config.
+    pub compression_level: Option<CompressionLevel>,
+    /// Size of garage's data blocks in bytes. `None` keeps the block size of
+    /// the template or existing config.
+    pub block_size: Option<u64>,
+    pub replication_factor: u64,
+    pub zone: String,
+    /// Storage capacity of the node in bytes.
+    pub node_capacity: u64,
+    pub node_tags: Vec<String>,
+    pub rpc_secret: Option<Secret>,
+    pub rpc_secret_path: PathBuf,
+    pub admin_token: Secret,
+    /// `None` if the metrics token is disabled.
+    pub metrics_token: Option<Secret>,
     pub access_key_id: String,
-    pub secret_access_key: String,
+    pub secret_access_key: Secret,
+    /// Whether the access key was generated because neither
+    /// `GARAGE_ACCESS_KEY_ID` nor `GARAGE_SECRET_ACCESS_KEY` is set.
+    pub access_key_generated: bool,
     pub buckets: Vec<BucketConfig>,
+    pub start_timeout: Duration,
+    pub version_check: VersionCheck,
+    pub start_poll_interval: Duration,
+    /// How often "Waiting for garage..." is logged.
+    pub start_log_interval: Duration,
+    /// Log "Waiting for garage..." only once instead of every interval.
+    pub start_log_once: bool,
+    pub shutdown_grace: Duration,
+    /// Timeout of admin API requests made while bootstrapping.
+    pub admin_timeout: Duration,
+    /// Interval in which access key and buckets are reconciled again while
+    /// garage runs, `None` to only do it once.
+    pub reconcile_interval: Option<Duration>,
+    /// How often idempotent admin API requests are retried on connection
+    /// errors and timeouts.
+    pub api_retries: u32,
+    pub prune_buckets: bool,
+    pub bucket_mode: BucketMode,
+    pub reset_keys: bool,
+    pub manage_layout: bool,
+    /// Launch a table and block repair after setting up the layout.
+    pub repair_on_start: bool,
+    pub manage_keys: bool,
+    pub manage_buckets: bool,
+    pub bucket_concurrency: usize,
+    pub health_addr: Option<SocketAddr>,
+    pub metrics_proxy_addr: Option<SocketAddr>,
+    /// Bind addresses written to the garage config. `None` keeps the one of
+    /// the template or existing config.
+    pub s3_api_bind: Option<SocketAddr>,
+    pub web_bind: Option<SocketAddr>,
+    pub admin_bind: Option<SocketAddr>,
+    /// URL the bootstrap reaches the admin API at, e.g. a TLS-terminating
+    /// sidecar. `None` derives it from `admin_bind`.
+    pub admin_url: Option<String>,
+    /// Additional root certificates trusted for an `https://` admin URL.
+    pub admin_ca_certs: Vec<reqwest::Certificate>,
+    /// Root domains for virtual-hosted-style S3 requests and websites. `None`
+    /// keeps the one of the template or existing config.
+    pub s3_root_domain: Option<String>,
+    pub web_root_domain: Option<String>,
+    pub restart_on_failure: bool,
+    pub max_restarts: u32,
+    pub restart_backoff: Duration,
+    pub dry_run: bool,
+    /// Where to write the effective credentials as JSON, `-` for stdout.
+    pub credentials_out: Option<PathBuf>,
+    /// File that exists while garage is bootstrapped.
+    pub ready_file: Option<PathBuf>,
 }
 
+/// A string which is shown as `***` when formatted, so it does not end up in
+/// logs by accident. Use [`Secret::expose`] to access the value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+/// Display view of a [`Config`] with all secrets redacted, see
+/// [`Config::redacted`].
+pub struct RedactedConfig<'a>(&'a Config);
+
+impl fmt::Display for RedactedConfig<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#?}", self.0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct BucketConfig {
     pub name: String,
+    #[serde(default)]
     pub policy: BucketPolicy,
+    #[serde(alias = "quota")]
+    pub quotas: Option<BucketQuotas>,
+    pub index_document: Option<String>,
+    pub error_document: Option<String>,
+    /// Serve the index document for missing paths so client-side routing of
+    /// single-page apps works. Garage answers these requests with status 404.
+    #[serde(default)]
+    pub spa: bool,
+    /// CORS rules applied via the S3 API. `None` leaves the current rules
+    /// untouched, an empty list removes them.
+    pub cors: Option<Vec<CorsRule>>,
+    /// Access key permissions on this bucket. `None` makes the configured
+    /// access key owner with read and write access.
+    pub permissions: Option<Vec<BucketKeyPermissions>>,
+    /// Makes `name` a local alias of an access key instead of a global alias.
+    pub local_alias: Option<LocalAlias>,
+    /// Global aliases in addition to `name`. `None` leaves the aliases of the
+    /// bucket untouched, otherwise all other global aliases are removed.
+    pub aliases: Option<Vec<String>>,
+    /// Days after which objects expire, applied via the S3 API. `None`
+    /// leaves the lifecycle configuration untouched.
+    pub expire_after_days: Option<u32>,
+    /// Object lock with a default retention. Garage does not implement
+    /// object lock, so any value is rejected during validation.
+    pub object_lock: Option<ObjectLock>,
+    /// Labels for billing or automation. Garage buckets cannot store them,
+    /// they are only reported on `/wrapper-metrics`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl BucketConfig {
+    /// Creates a bucket without any further settings, like a plain
+    /// `GARAGE_BUCKETS` entry.
+    pub fn new(name: impl Into<String>, policy: BucketPolicy) -> Self {
+        Self {
+            name: name.into(),
+            policy,
+            quotas: None,
+            index_document: None,
+            error_document: None,
+            spa: false,
+            cors: None,
+            permissions: None,
+            local_alias: None,
+            aliases: None,
+            expire_after_days: None,
+            object_lock: None,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the configured permissions or the default grant.
+    pub fn permissions(&self) -> Vec<BucketKeyPermissions> {
+        self.permissions.clone().unwrap_or_else(|| {
+            vec![BucketKeyPermissions {
+                key: None,
+                owner: true,
+                read: true,
+                write: true,
+            }]
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalAlias {
+    /// Access key id owning the alias; default is `GARAGE_ACCESS_KEY_ID`.
+    pub key: Option<String>,
+}
+
+/// Permissions of one access key on a bucket. Permissions which are not
+/// enabled are revoked.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketKeyPermissions {
+    /// Access key id; default is `GARAGE_ACCESS_KEY_ID`.
+    pub key: Option<String>,
+    #[serde(default)]
+    pub owner: bool,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default)]
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsRule {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    pub max_age_seconds: Option<u64>,
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, EnumString)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectLock {
+    pub mode: ObjectLockMode,
+    pub days: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ObjectLockMode {
+    Governance,
+    Compliance,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketQuotas {
+    /// Bytes, given as a number or a string like `10GiB` in JSON.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    pub max_size: Option<u64>,
+    pub max_objects: Option<u64>,
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize, EnumString)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case", ascii_case_insensitive)]
 pub enum BucketPolicy {
+    #[default]
     Private,
+    /// Website access enabled. Garage has no anonymous access through the S3
+    /// API, so this only makes objects readable via the web endpoint.
     Public,
 }
 
+/// How an already existing garage config file is treated.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum ConfigMode {
+    /// Replace the file with the bundled template.
+    #[default]
+    Overwrite,
+    /// Keep the file as is and skip writing it.
+    Preserve,
+    /// Only update the fields managed by this tool.
+    Merge,
+}
+
+/// How buckets which already exist in garage are treated.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, EnumString)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum BucketMode {
+    /// Update settings, aliases and permissions to match the config.
+    #[default]
+    Reconcile,
+    /// Leave existing buckets untouched, only new ones are configured.
+    CreateOnly,
+}
+
+/// What happens if garage reports a version the admin client does not
+/// support.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum VersionCheck {
+    Off,
+    #[default]
+    Warn,
+    Error,
+}
+
+/// Metadata database engine used by garage.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, EnumString, strum::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum DbEngine {
+    #[default]
+    Sqlite,
+    Lmdb,
+}
+
+/// zstd compression level garage stores data blocks with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// Store blocks uncompressed.
+    None,
+    /// Negative levels are faster, up to 22 compresses best.
+    Level(i32),
+}
+
+impl FromStr for CompressionLevel {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("none") {
+            return Ok(Self::None);
+        }
+        match value.parse::<i32>() {
+            Ok(level) if level <= 22 => Ok(Self::Level(level)),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("missing environment variable {name}")]
@@ -36,66 +385,724 @@ pub enum ConfigError {
     EmptyVar { name: &'static str },
     #[error("environment variable {name} is not valid unicode")]
     InvalidUnicode { name: &'static str },
+    #[error("environment variable {name}_FILE is not valid unicode")]
+    InvalidUnicodeFileVar { name: &'static str },
+    #[error("file {path:?} of {name}_FILE is not valid UTF-8")]
+    InvalidUnicodeFile { name: &'static str, path: PathBuf },
+    #[error("both {name} and {name}_FILE are set")]
+    ConflictingVars { name: &'static str },
+    #[error("could not read {name}_FILE {path:?}")]
+    ReadFile {
+        name: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not read {name} {path:?}")]
+    ReadCaCert {
+        name: &'static str,
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{name} {path:?} contains an invalid certificate")]
+    InvalidCaCert {
+        name: &'static str,
+        path: PathBuf,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("{name} {path:?} contains no PEM certificate")]
+    NoCaCert { name: &'static str, path: PathBuf },
+    #[error(
+        "GARAGE_ADMIN_URL {url} is not a loopback address, set GARAGE_ADMIN_ALLOW_REMOTE=true to allow it"
+    )]
+    RemoteAdminUrl { url: String },
+    #[error("GARAGE_ADMIN_TOKEN and GARAGE_METRICS_TOKEN must not be the same")]
+    TokenCollision,
+    #[error("GARAGE_PRUNE_BUCKETS would delete every bucket because no buckets are configured")]
+    PruneWithoutBuckets,
+    #[error("{set} is set without {missing}, set both or neither")]
+    IncompleteAccessKey {
+        set: &'static str,
+        missing: &'static str,
+    },
+    #[error("invalid access key id {value:?}: {reason}")]
+    InvalidAccessKeyId { value: String, reason: &'static str },
+    #[error("invalid secret access key: must be 64 hex digits")]
+    InvalidSecretAccessKey,
+    #[error("could not generate random value")]
+    Random(#[from] getrandom::Error),
+    #[error("environment variable {name} is not a valid number: {value}")]
+    InvalidNumber { name: &'static str, value: String },
+    #[error("environment variable {name} must be at least {min}: {value}")]
+    NumberTooSmall {
+        name: &'static str,
+        value: u64,
+        min: u64,
+    },
+    #[error("environment variable {name} is not a valid boolean: {value}")]
+    InvalidBool { name: &'static str, value: String },
+    #[error("environment variable {name} has an invalid value: {value}")]
+    InvalidValue { name: &'static str, value: String },
+    #[error("environment variable GARAGE_BUCKETS_JSON is not a valid bucket list")]
+    InvalidBucketsJson {
+        #[source]
+        source: serde_json::Error,
+    },
     #[error("invalid bucket entry {entry}")]
     InvalidBucketEntry { entry: String },
-    #[error("invalid bucket name {name}")]
-    InvalidBucketName { name: String },
-    #[error("invalid bucket policy {value} for bucket {bucket}")]
+    #[error("invalid bucket name {name:?}: {reason}")]
+    InvalidBucketName { name: String, reason: &'static str },
+    #[error("bucket {name} is listed more than once")]
+    DuplicateBucket { name: String },
+    #[error("invalid bucket policy {value:?} for bucket {bucket}")]
     InvalidBucketPolicy { bucket: String, value: String },
+    #[error("permissions of key {key} are listed more than once for bucket {bucket}")]
+    DuplicateBucketKey { bucket: String, key: String },
+    #[error("invalid CORS rule for bucket {bucket}: {reason}")]
+    InvalidCorsRule {
+        bucket: String,
+        reason: &'static str,
+    },
+    #[error("unknown option {option} for bucket {bucket}")]
+    UnknownBucketOption { bucket: String, option: String },
+    #[error("invalid value {value} for option {option} of bucket {bucket}")]
+    InvalidBucketOption {
+        bucket: String,
+        option: String,
+        value: String,
+    },
+    #[error("option {option} of bucket {bucket} is not supported by garage")]
+    UnsupportedBucketOption {
+        bucket: String,
+        option: &'static str,
+    },
+    #[error(
+        "option {option} of bucket {bucket} is applied with GARAGE_ACCESS_KEY_ID, which its permissions do not make owner"
+    )]
+    S3OptionWithoutOwner {
+        bucket: String,
+        option: &'static str,
+    },
+    #[error(
+        "option {option} of bucket {bucket} is applied with GARAGE_ACCESS_KEY_ID, which cannot address the local alias of key {key}"
+    )]
+    S3OptionOnForeignLocalAlias {
+        bucket: String,
+        option: &'static str,
+        key: String,
+    },
+    #[error(
+        "bucket {bucket} sets both spa and error_document, spa serves the index document instead"
+    )]
+    SpaWithErrorDocument { bucket: String },
+    #[error("{} config errors:{}", .0.len(), format_errors(.0))]
+    Multiple(Vec<ConfigError>),
+}
+
+impl ConfigError {
+    /// Combines `errors` into a single error, `None` if there are none.
+    pub fn from_errors(mut errors: Vec<ConfigError>) -> Option<Self> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(ConfigError::Multiple(errors)),
+        }
+    }
+}
+
+/// Lists every error with its sources on a line of its own.
+fn format_errors(errors: &[ConfigError]) -> String {
+    let mut output = String::new();
+    for error in errors {
+        output.push_str("\n- ");
+        output.push_str(&error.to_string());
+        let mut source = std::error::Error::source(error);
+        while let Some(cause) = source {
+            output.push_str(": ");
+            output.push_str(&cause.to_string());
+            source = cause.source();
+        }
+    }
+    output
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, ConfigError> {
-        let garage_admin_token = read_env_default("GARAGE_ADMIN_TOKEN", || random_base64(32))?;
-        let garage_metrics_token = read_env_default("GARAGE_METRICS_TOKEN", || random_base64(32))?;
-        let garage_access_key_id = read_env("GARAGE_ACCESS_KEY_ID")?;
-        let garage_secret_access_key = read_env("GARAGE_SECRET_ACCESS_KEY")?;
-        let garage_buckets_raw = read_env("GARAGE_BUCKETS")?;
-
-        let mut garage_buckets = Vec::new();
-        for raw_entry in garage_buckets_raw.split(',') {
-            let entry = raw_entry.trim();
-            if entry.is_empty() {
-                return Err(ConfigError::InvalidBucketEntry {
-                    entry: raw_entry.to_string(),
+        Self::from_vars(&|name| env::var(name), None)
+    }
+
+    /// Like [`Config::from_env`], but reads the variables from `vars`
+    /// instead of the process environment, e.g. to build a config in code or
+    /// in tests running in parallel.
+    pub fn from_map(vars: &HashMap<String, String>) -> Result<Self, ConfigError> {
+        Self::from_vars(
+            &|name| vars.get(name).cloned().ok_or(env::VarError::NotPresent),
+            None,
+        )
+    }
+
+    /// Reads the config from `vars`. `buckets` replaces `GARAGE_BUCKETS` and
+    /// `GARAGE_BUCKETS_JSON` if set.
+    fn from_vars(vars: &Vars, buckets: Option<Vec<BucketConfig>>) -> Result<Self, ConfigError> {
+        let mut errors = Vec::new();
+        let garage_binary = collect(
+            &mut errors,
+            read_env_default(vars, "GARAGE_BINARY", || DEFAULT_GARAGE_BINARY.into()),
+        );
+        let garage_extra_args =
+            match collect(&mut errors, read_env_optional(vars, "GARAGE_EXTRA_ARGS")) {
+                Some(value) => match shell_words::split(&value) {
+                    Ok(args) => args,
+                    Err(_) => {
+                        errors.push(ConfigError::InvalidValue {
+                            name: "GARAGE_EXTRA_ARGS",
+                            value,
+                        });
+                        Vec::new()
+                    }
+                },
+                None => Vec::new(),
+            };
+        let garage_config_path = collect(
+            &mut errors,
+            read_env_default(vars, "GARAGE_CONFIG_PATH", || DEFAULT_CONFIG_PATH.into()),
+        );
+        let garage_config_mode = collect(
+            &mut errors,
+            read_env_parse(vars, "GARAGE_CONFIG_MODE", ConfigMode::Overwrite),
+        );
+        let garage_config_extra_path = collect(
+            &mut errors,
+            read_env_optional(vars, "GARAGE_CONFIG_EXTRA_PATH"),
+        );
+        let garage_rpc_secret = collect(
+            &mut errors,
+            missing_as_none(read_env_or_file(vars, "GARAGE_RPC_SECRET")),
+        );
+        let garage_metadata_dir = PathBuf::from(collect(
+            &mut errors,
+            read_env_default(vars, "GARAGE_METADATA_DIR", || DEFAULT_METADATA_DIR.into()),
+        ));
+        let garage_db_engine = collect(
+            &mut errors,
+            read_env_parse(vars, "GARAGE_DB_ENGINE", DbEngine::Sqlite),
+        );
+        let garage_data_dir = collect(
+            &mut errors,
+            read_env_default(vars, "GARAGE_DATA_DIR", || DEFAULT_DATA_DIR.into()),
+        );
+        let garage_compression_level = collect(
+            &mut errors,
+            read_env_parse_optional(vars, "GARAGE_COMPRESSION_LEVEL"),
+        );
+        let garage_block_size =
+            match collect(&mut errors, read_env_optional(vars, "GARAGE_BLOCK_SIZE")) {
+                Some(value) => match parse_size(&value) {
+                    Some(block_size) if BLOCK_SIZE_RANGE.contains(&block_size) => Some(block_size),
+                    _ => {
+                        errors.push(ConfigError::InvalidValue {
+                            name: "GARAGE_BLOCK_SIZE",
+                            value,
+                        });
+                        None
+                    }
+                },
+                None => None,
+            };
+        let garage_replication_factor = collect(
+            &mut errors,
+            read_env_positive(
+                vars,
+                "GARAGE_REPLICATION_FACTOR",
+                DEFAULT_REPLICATION_FACTOR,
+            ),
+        );
+        // GARAGE_NODE_ZONE matches the other GARAGE_NODE_* variables,
+        // GARAGE_ZONE is still accepted.
+        let garage_zone = match collect(&mut errors, read_env_optional(vars, "GARAGE_NODE_ZONE")) {
+            Some(zone) => {
+                if !matches!(vars("GARAGE_ZONE"), Err(env::VarError::NotPresent)) {
+                    warn!("GARAGE_NODE_ZONE is set, ignoring GARAGE_ZONE");
+                }
+                zone
+            }
+            None => collect(
+                &mut errors,
+                read_env_default(vars, "GARAGE_ZONE", || DEFAULT_ZONE.into()),
+            ),
+        };
+        let garage_node_capacity =
+            match collect(&mut errors, read_env_optional(vars, "GARAGE_NODE_CAPACITY")) {
+                Some(value) => match parse_size(&value) {
+                    Some(capacity) if capacity > 0 => capacity,
+                    _ => {
+                        errors.push(ConfigError::InvalidValue {
+                            name: "GARAGE_NODE_CAPACITY",
+                            value,
+                        });
+                        DEFAULT_NODE_CAPACITY
+                    }
+                },
+                None => DEFAULT_NODE_CAPACITY,
+            };
+        let garage_node_tags = collect(&mut errors, read_env_optional(vars, "GARAGE_NODE_TAGS"))
+            .map(|tags| {
+                tags.split(',')
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let garage_rpc_secret_path = match collect(
+            &mut errors,
+            read_env_optional(vars, "GARAGE_RPC_SECRET_PATH"),
+        ) {
+            Some(path) => PathBuf::from(path),
+            None => garage_metadata_dir.join("rpc_secret"),
+        };
+        let garage_token_bytes =
+            match read_env_number(vars, "GARAGE_TOKEN_BYTES", DEFAULT_TOKEN_BYTES) {
+                Ok(value) if value < MIN_TOKEN_BYTES => {
+                    errors.push(ConfigError::NumberTooSmall {
+                        name: "GARAGE_TOKEN_BYTES",
+                        value,
+                        min: MIN_TOKEN_BYTES,
+                    });
+                    DEFAULT_TOKEN_BYTES
+                }
+                Ok(token_bytes) => token_bytes,
+                Err(error) => {
+                    errors.push(error);
+                    DEFAULT_TOKEN_BYTES
+                }
+            };
+        let garage_admin_token = match collect(
+            &mut errors,
+            missing_as_none(read_env_or_file(vars, "GARAGE_ADMIN_TOKEN")),
+        ) {
+            Some(token) => token,
+            None => collect(
+                &mut errors,
+                random_token("GARAGE_ADMIN_TOKEN", garage_token_bytes as usize),
+            ),
+        };
+        let garage_metrics_token = match collect(
+            &mut errors,
+            missing_as_none(read_env_or_file(vars, "GARAGE_METRICS_TOKEN")),
+        ) {
+            Some(token) if token == METRICS_TOKEN_DISABLED => None,
+            Some(token) => Some(token),
+            None => Some(collect(
+                &mut errors,
+                random_token("GARAGE_METRICS_TOKEN", garage_token_bytes as usize),
+            )),
+        };
+        let mut garage_access_key_generated = false;
+        // `None` if the access key could not be read, its format is not
+        // checked then.
+        let garage_access_key = match (
+            missing_as_none(read_env_or_file(vars, "GARAGE_ACCESS_KEY_ID")),
+            missing_as_none(read_env_or_file(vars, "GARAGE_SECRET_ACCESS_KEY")),
+        ) {
+            (Ok(Some(access_key_id)), Ok(Some(secret_access_key))) => {
+                Some((access_key_id, secret_access_key))
+            }
+            (Ok(None), Ok(None)) => match (random_hex(12), random_hex(32)) {
+                (Ok(access_key_id), Ok(secret_access_key)) => {
+                    let access_key_id = format!("GK{}", access_key_id);
+                    warn!(
+                        "GARAGE_ACCESS_KEY_ID and GARAGE_SECRET_ACCESS_KEY are not set, generated \
+                         access key {:?} which changes on every start. Set GARAGE_CREDENTIALS_OUT \
+                         to get its secret access key.",
+                        access_key_id
+                    );
+                    garage_access_key_generated = true;
+                    Some((access_key_id, secret_access_key))
+                }
+                (access_key_id, secret_access_key) => {
+                    errors.extend(access_key_id.err().map(ConfigError::from));
+                    errors.extend(secret_access_key.err().map(ConfigError::from));
+                    None
+                }
+            },
+            (Ok(Some(_)), Ok(None)) => {
+                errors.push(ConfigError::IncompleteAccessKey {
+                    set: "GARAGE_ACCESS_KEY_ID",
+                    missing: "GARAGE_SECRET_ACCESS_KEY",
                 });
+                None
             }
-            let mut parts = entry.splitn(2, ':');
-            let name = parts.next().unwrap().trim();
-            if name.is_empty() || !is_valid_bucket_name(name) {
-                return Err(ConfigError::InvalidBucketName {
-                    name: name.to_string(),
+            (Ok(None), Ok(Some(_))) => {
+                errors.push(ConfigError::IncompleteAccessKey {
+                    set: "GARAGE_SECRET_ACCESS_KEY",
+                    missing: "GARAGE_ACCESS_KEY_ID",
                 });
+                None
             }
-
-            let policy = match parts.next() {
-                Some(value) => {
-                    BucketPolicy::from_str(value).map_err(|_| ConfigError::InvalidBucketPolicy {
-                        bucket: name.to_string(),
-                        value: value.to_string(),
-                    })?
-                }
-                None => BucketPolicy::Private,
-            };
-
-            garage_buckets.push(BucketConfig {
-                name: name.to_string(),
-                policy,
+            (access_key_id, secret_access_key) => {
+                errors.extend(access_key_id.err());
+                errors.extend(secret_access_key.err());
+                None
+            }
+        };
+        let garage_buckets = match buckets {
+            Some(buckets) => buckets,
+            None => read_buckets(vars, &mut errors),
+        };
+        let garage_start_timeout_secs = collect(
+            &mut errors,
+            read_env_number(
+                vars,
+                "GARAGE_START_TIMEOUT_SECS",
+                DEFAULT_START_TIMEOUT_SECS,
+            ),
+        );
+        let garage_start_poll_interval_ms = collect(
+            &mut errors,
+            read_env_positive(
+                vars,
+                "GARAGE_START_POLL_INTERVAL_MS",
+                DEFAULT_START_POLL_INTERVAL_MS,
+            ),
+        );
+        let garage_start_log_interval_secs = collect(
+            &mut errors,
+            read_env_positive(
+                vars,
+                "GARAGE_START_LOG_INTERVAL_SECS",
+                DEFAULT_START_LOG_INTERVAL_SECS,
+            ),
+        );
+        let garage_start_log_once = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_START_LOG_ONCE", false),
+        );
+        let garage_version_check = collect(
+            &mut errors,
+            read_env_parse(vars, "GARAGE_VERSION_CHECK", VersionCheck::Warn),
+        );
+        let garage_shutdown_grace_secs = collect(
+            &mut errors,
+            read_env_number(
+                vars,
+                "GARAGE_SHUTDOWN_GRACE_SECS",
+                DEFAULT_SHUTDOWN_GRACE_SECS,
+            ),
+        );
+        let garage_admin_timeout_secs = collect(
+            &mut errors,
+            read_env_positive(
+                vars,
+                "GARAGE_ADMIN_TIMEOUT_SECS",
+                DEFAULT_ADMIN_TIMEOUT_SECS,
+            ),
+        );
+        let garage_api_retries = collect(
+            &mut errors,
+            read_env_number(vars, "GARAGE_API_RETRIES", DEFAULT_API_RETRIES),
+        );
+        let garage_reconcile_interval_secs = collect(
+            &mut errors,
+            read_env_parse_optional::<u64>(vars, "GARAGE_RECONCILE_INTERVAL_SECS"),
+        );
+        if garage_reconcile_interval_secs == Some(0) {
+            errors.push(ConfigError::InvalidNumber {
+                name: "GARAGE_RECONCILE_INTERVAL_SECS",
+                value: "0".into(),
             });
         }
+        let garage_prune_buckets = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_PRUNE_BUCKETS", false),
+        );
+        let garage_bucket_mode = collect(
+            &mut errors,
+            read_env_parse(vars, "GARAGE_BUCKET_MODE", BucketMode::Reconcile),
+        );
+        let garage_reset_keys =
+            collect(&mut errors, read_env_bool(vars, "GARAGE_RESET_KEYS", false));
+        let garage_manage_layout = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_MANAGE_LAYOUT", true),
+        );
+        let garage_repair_on_start = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_RUN_REPAIR_ON_START", false),
+        );
+        let garage_manage_keys =
+            collect(&mut errors, read_env_bool(vars, "GARAGE_MANAGE_KEYS", true));
+        let garage_manage_buckets = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_MANAGE_BUCKETS", true),
+        );
+        let garage_bucket_concurrency = collect(
+            &mut errors,
+            read_env_positive(
+                vars,
+                "GARAGE_BUCKET_CONCURRENCY",
+                DEFAULT_BUCKET_CONCURRENCY,
+            ),
+        );
+        let garage_s3_api_bind = collect(
+            &mut errors,
+            read_env_parse_optional(vars, "GARAGE_S3_API_BIND"),
+        );
+        let garage_web_bind = collect(
+            &mut errors,
+            read_env_parse_optional(vars, "GARAGE_WEB_BIND"),
+        );
+        let garage_admin_bind = collect(
+            &mut errors,
+            read_env_parse_optional(vars, "GARAGE_ADMIN_BIND"),
+        );
+        let garage_admin_url = collect(&mut errors, read_env_optional(vars, "GARAGE_ADMIN_URL"));
+        let garage_admin_allow_remote = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_ADMIN_ALLOW_REMOTE", false),
+        );
+        if let Some(value) = &garage_admin_url {
+            match reqwest::Url::parse(value) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {
+                    if !is_loopback(&url) {
+                        if !garage_admin_allow_remote {
+                            errors.push(ConfigError::RemoteAdminUrl { url: value.clone() });
+                        } else if url.scheme() == "http" {
+                            warn!(
+                                "GARAGE_ADMIN_URL {} is not a loopback address, the admin token is sent unencrypted",
+                                value
+                            );
+                        }
+                    }
+                }
+                _ => errors.push(ConfigError::InvalidValue {
+                    name: "GARAGE_ADMIN_URL",
+                    value: value.clone(),
+                }),
+            }
+        }
+        let garage_admin_ca_certs =
+            collect(&mut errors, read_ca_certs(vars, "GARAGE_ADMIN_CA_CERT"));
+        let garage_metrics_proxy_addr = collect(
+            &mut errors,
+            read_env_parse_optional(vars, "GARAGE_METRICS_PROXY_ADDR"),
+        );
+        let garage_s3_root_domain = collect(
+            &mut errors,
+            read_env_optional(vars, "GARAGE_S3_ROOT_DOMAIN"),
+        );
+        let garage_web_root_domain = collect(
+            &mut errors,
+            read_env_optional(vars, "GARAGE_WEB_ROOT_DOMAIN"),
+        );
+        let garage_health_addr = collect(
+            &mut errors,
+            read_env_parse_optional(vars, "GARAGE_HEALTH_ADDR"),
+        );
+        let garage_restart_on_failure = collect(
+            &mut errors,
+            read_env_bool(vars, "GARAGE_RESTART_ON_FAILURE", false),
+        );
+        let garage_max_restarts = collect(
+            &mut errors,
+            read_env_number(vars, "GARAGE_MAX_RESTARTS", DEFAULT_MAX_RESTARTS),
+        );
+        let garage_restart_backoff_secs = collect(
+            &mut errors,
+            read_env_number(
+                vars,
+                "GARAGE_RESTART_BACKOFF_SECS",
+                DEFAULT_RESTART_BACKOFF_SECS,
+            ),
+        );
+        let garage_dry_run = collect(&mut errors, read_env_bool(vars, "GARAGE_DRY_RUN", false));
+        let garage_ready_file = collect(&mut errors, read_env_optional(vars, "GARAGE_READY_FILE"));
+        let garage_credentials_out = collect(
+            &mut errors,
+            read_env_optional(vars, "GARAGE_CREDENTIALS_OUT"),
+        );
+        let garage_access_key_read = garage_access_key.is_some();
+        let (garage_access_key_id, garage_secret_access_key) =
+            garage_access_key.unwrap_or_default();
 
-        Ok(Self {
-            admin_token: garage_admin_token,
-            metrics_token: garage_metrics_token,
+        let config = Self {
+            garage_binary: PathBuf::from(garage_binary),
+            extra_args: garage_extra_args,
+            config_path: PathBuf::from(garage_config_path),
+            config_mode: garage_config_mode,
+            config_extra_path: garage_config_extra_path.map(PathBuf::from),
+            metadata_dir: garage_metadata_dir,
+            data_dir: PathBuf::from(garage_data_dir),
+            db_engine: garage_db_engine,
+            compression_level: garage_compression_level,
+            block_size: garage_block_size,
+            replication_factor: garage_replication_factor,
+            zone: garage_zone,
+            node_capacity: garage_node_capacity,
+            node_tags: garage_node_tags,
+            rpc_secret: garage_rpc_secret.map(Secret::from),
+            rpc_secret_path: garage_rpc_secret_path,
+            admin_token: Secret::from(garage_admin_token),
+            metrics_token: garage_metrics_token.map(Secret::from),
             access_key_id: garage_access_key_id,
-            secret_access_key: garage_secret_access_key,
+            secret_access_key: Secret::from(garage_secret_access_key),
+            access_key_generated: garage_access_key_generated,
             buckets: garage_buckets,
-        })
+            start_timeout: Duration::from_secs(garage_start_timeout_secs),
+            version_check: garage_version_check,
+            start_poll_interval: Duration::from_millis(garage_start_poll_interval_ms),
+            start_log_interval: Duration::from_secs(garage_start_log_interval_secs),
+            start_log_once: garage_start_log_once,
+            shutdown_grace: Duration::from_secs(garage_shutdown_grace_secs),
+            admin_timeout: Duration::from_secs(garage_admin_timeout_secs),
+            reconcile_interval: garage_reconcile_interval_secs.map(Duration::from_secs),
+            api_retries: u32::try_from(garage_api_retries).unwrap_or(u32::MAX),
+            prune_buckets: garage_prune_buckets,
+            bucket_mode: garage_bucket_mode,
+            reset_keys: garage_reset_keys,
+            manage_layout: garage_manage_layout,
+            repair_on_start: garage_repair_on_start,
+            manage_keys: garage_manage_keys,
+            manage_buckets: garage_manage_buckets,
+            bucket_concurrency: garage_bucket_concurrency as usize,
+            health_addr: garage_health_addr,
+            metrics_proxy_addr: garage_metrics_proxy_addr,
+            s3_api_bind: garage_s3_api_bind,
+            web_bind: garage_web_bind,
+            admin_bind: garage_admin_bind,
+            admin_url: garage_admin_url.map(|url| url.trim_end_matches('/').to_string()),
+            admin_ca_certs: garage_admin_ca_certs,
+            s3_root_domain: garage_s3_root_domain,
+            web_root_domain: garage_web_root_domain,
+            restart_on_failure: garage_restart_on_failure,
+            max_restarts: u32::try_from(garage_max_restarts).unwrap_or(u32::MAX),
+            restart_backoff: Duration::from_secs(garage_restart_backoff_secs),
+            dry_run: garage_dry_run,
+            credentials_out: garage_credentials_out.map(PathBuf::from),
+            ready_file: garage_ready_file.map(PathBuf::from),
+        };
+        validate_buckets(&config.buckets, &mut errors);
+        if garage_access_key_read {
+            validate_access_key(&config, &mut errors);
+        }
+        validate_tokens(&config, &mut errors);
+        validate_prune(&config, &mut errors);
+        validate_s3_owner(&config, &mut errors);
+        match ConfigError::from_errors(errors) {
+            Some(error) => Err(error),
+            None => Ok(config),
+        }
+    }
+
+    /// Runs the checks which do not need a running garage: bucket names and
+    /// duplicates, bucket options, access key format, token collisions,
+    /// pruning without buckets and S3 options without owner permission.
+    /// [`Config::from_env`] already runs them, this is for a config changed
+    /// after loading and returns the problems as a list instead of a single
+    /// [`ConfigError::Multiple`].
+    pub fn validate(&self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        validate_buckets(&self.buckets, &mut errors);
+        validate_access_key(self, &mut errors);
+        validate_tokens(self, &mut errors);
+        validate_prune(self, &mut errors);
+        validate_s3_owner(self, &mut errors);
+        errors
+    }
+
+    /// Returns a view of the config that is safe to log.
+    pub fn redacted(&self) -> RedactedConfig<'_> {
+        RedactedConfig(self)
+    }
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+/// Builds a [`Config`] in code instead of from the environment. Settings
+/// without a dedicated method are set with [`ConfigBuilder::var`] using the
+/// name of their environment variable. Only the buckets added to the builder
+/// are managed, `GARAGE_BUCKETS` and `GARAGE_BUCKETS_JSON` are ignored.
+#[derive(Default)]
+pub struct ConfigBuilder {
+    vars: HashMap<String, String>,
+    buckets: Vec<BucketConfig>,
+}
+
+impl fmt::Debug for ConfigBuilder {
+    /// Values of variables whose name contains `TOKEN` or `SECRET` are
+    /// redacted like a [`Secret`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = Secret(String::new());
+        let vars: BTreeMap<_, _> = self
+            .vars
+            .iter()
+            .map(|(name, value)| {
+                let value: &dyn fmt::Debug = if name.contains("TOKEN") || name.contains("SECRET") {
+                    &redacted
+                } else {
+                    value
+                };
+                (name, value)
+            })
+            .collect();
+        f.debug_struct("ConfigBuilder")
+            .field("vars", &vars)
+            .field("buckets", &self.buckets)
+            .finish()
+    }
+}
+
+impl ConfigBuilder {
+    /// Sets the variable `name`, e.g. `GARAGE_METADATA_DIR`.
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn admin_token(self, token: impl Into<String>) -> Self {
+        self.var("GARAGE_ADMIN_TOKEN", token)
+    }
+
+    pub fn metrics_token(self, token: impl Into<String>) -> Self {
+        self.var("GARAGE_METRICS_TOKEN", token)
+    }
+
+    pub fn access_key(self, id: impl Into<String>, secret: impl Into<String>) -> Self {
+        self.var("GARAGE_ACCESS_KEY_ID", id)
+            .var("GARAGE_SECRET_ACCESS_KEY", secret)
+    }
+
+    pub fn add_bucket(self, name: impl Into<String>, policy: BucketPolicy) -> Self {
+        self.bucket(BucketConfig::new(name, policy))
+    }
+
+    /// Adds a bucket with settings beyond its policy, e.g. quotas or CORS
+    /// rules.
+    pub fn bucket(mut self, bucket: BucketConfig) -> Self {
+        self.buckets.push(bucket);
+        self
+    }
+
+    /// Validates the settings like [`Config::from_env`] and returns the
+    /// config.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let vars = self.vars;
+        Config::from_vars(
+            &|name| vars.get(name).cloned().ok_or(env::VarError::NotPresent),
+            Some(self.buckets),
+        )
     }
 }
 
-fn read_env(name: &'static str) -> Result<String, ConfigError> {
-    match env::var(name) {
+/// Looks up a configuration variable, like [`env::var`].
+type Vars<'a> = dyn Fn(&str) -> Result<String, env::VarError> + 'a;
+
+fn read_env(vars: &Vars, name: &'static str) -> Result<String, ConfigError> {
+    match vars(name) {
         Ok(value) => {
             let trimmed = value.trim().to_string();
             if trimmed.is_empty() {
@@ -109,19 +1116,584 @@ fn read_env(name: &'static str) -> Result<String, ConfigError> {
     }
 }
 
-fn read_env_default(name: &'static str, default: fn() -> String) -> Result<String, ConfigError> {
-    match read_env(name) {
+/// Reads `name` or, if `{name}_FILE` is set, the trimmed contents of the file
+/// it points to. Setting both is an error.
+fn read_env_or_file(vars: &Vars, name: &'static str) -> Result<String, ConfigError> {
+    let file_var = format!("{}_FILE", name);
+    let path = match vars(&file_var) {
+        Ok(path) if !path.trim().is_empty() => PathBuf::from(path.trim()),
+        Ok(_) | Err(env::VarError::NotPresent) => return read_env(vars, name),
+        Err(env::VarError::NotUnicode(_)) => {
+            return Err(ConfigError::InvalidUnicodeFileVar { name });
+        }
+    };
+    if !matches!(vars(name), Err(env::VarError::NotPresent)) {
+        return Err(ConfigError::ConflictingVars { name });
+    }
+    let value = match fs::read_to_string(&path) {
+        Ok(value) => value,
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            return Err(ConfigError::InvalidUnicodeFile { name, path });
+        }
+        Err(source) => return Err(ConfigError::ReadFile { name, path, source }),
+    };
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        Err(ConfigError::EmptyVar { name })
+    } else {
+        Ok(trimmed.to_string())
+    }
+}
+
+/// Whether `url` points to this host, by a loopback IP address or a
+/// `localhost` name.
+fn is_loopback(url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            host == "localhost" || host.ends_with(".localhost")
+        }
+    }
+}
+
+/// Loads the PEM certificates of the file `name` points to, none if unset.
+fn read_ca_certs(
+    vars: &Vars,
+    name: &'static str,
+) -> Result<Vec<reqwest::Certificate>, ConfigError> {
+    let Some(path) = read_env_optional(vars, name)?.map(PathBuf::from) else {
+        return Ok(Vec::new());
+    };
+    let pem = fs::read(&path).map_err(|source| ConfigError::ReadCaCert {
+        name,
+        path: path.clone(),
+        source,
+    })?;
+    match reqwest::Certificate::from_pem_bundle(&pem) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        Ok(_) => Err(ConfigError::NoCaCert { name, path }),
+        Err(source) => Err(ConfigError::InvalidCaCert { name, path, source }),
+    }
+}
+
+/// Reads the buckets from `GARAGE_BUCKETS_JSON` or, if unset, `GARAGE_BUCKETS`.
+/// No buckets are managed if neither is set.
+fn read_buckets(vars: &Vars, errors: &mut Vec<ConfigError>) -> Vec<BucketConfig> {
+    match collect(
+        errors,
+        missing_as_none(read_env_or_file(vars, "GARAGE_BUCKETS_JSON")),
+    ) {
+        Some(json) => {
+            if !matches!(vars("GARAGE_BUCKETS"), Err(env::VarError::NotPresent)) {
+                warn!("GARAGE_BUCKETS_JSON is set, ignoring GARAGE_BUCKETS");
+            }
+            collect(
+                errors,
+                serde_json::from_str(&json)
+                    .map_err(|source| ConfigError::InvalidBucketsJson { source }),
+            )
+        }
+        None => match collect(errors, read_env_optional(vars, "GARAGE_BUCKETS")) {
+            Some(raw) => parse_buckets(&raw, errors),
+            None => Vec::new(),
+        },
+    }
+}
+
+fn missing_as_none(result: Result<String, ConfigError>) -> Result<Option<String>, ConfigError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::MissingVar { .. }) => Ok(None),
+        Err(ConfigError::EmptyVar { .. }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_env_optional(vars: &Vars, name: &'static str) -> Result<Option<String>, ConfigError> {
+    missing_as_none(read_env(vars, name))
+}
+
+fn read_env_default(
+    vars: &Vars,
+    name: &'static str,
+    default: fn() -> String,
+) -> Result<String, ConfigError> {
+    match read_env(vars, name) {
         Err(ConfigError::MissingVar { .. }) => Ok(default()),
         Err(ConfigError::EmptyVar { .. }) => Ok(default()),
         x => x,
     }
 }
 
-fn is_valid_bucket_name(name: &str) -> bool {
-    let mut chars = name.chars();
-    match chars.next() {
-        Some(first) if first.is_ascii_alphabetic() => (),
-        _ => return false,
+fn read_env_number(vars: &Vars, name: &'static str, default: u64) -> Result<u64, ConfigError> {
+    match read_env(vars, name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| ConfigError::InvalidNumber { name, value }),
+        Err(ConfigError::MissingVar { .. }) => Ok(default),
+        Err(ConfigError::EmptyVar { .. }) => Ok(default),
+        Err(e) => Err(e),
+    }
+}
+
+/// Like [`read_env_number`], but rejects 0.
+fn read_env_positive(vars: &Vars, name: &'static str, default: u64) -> Result<u64, ConfigError> {
+    match read_env_number(vars, name, default)? {
+        0 => Err(ConfigError::NumberTooSmall {
+            name,
+            value: 0,
+            min: 1,
+        }),
+        value => Ok(value),
+    }
+}
+
+fn read_env_bool(vars: &Vars, name: &'static str, default: bool) -> Result<bool, ConfigError> {
+    match read_env(vars, name) {
+        Ok(value) => parse_bool(&value).ok_or(ConfigError::InvalidBool { name, value }),
+        Err(ConfigError::MissingVar { .. }) => Ok(default),
+        Err(ConfigError::EmptyVar { .. }) => Ok(default),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Some(true),
+        "false" | "0" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn read_env_parse<T: FromStr>(
+    vars: &Vars,
+    name: &'static str,
+    default: T,
+) -> Result<T, ConfigError> {
+    match read_env(vars, name) {
+        Ok(value) => T::from_str(&value).map_err(|_| ConfigError::InvalidValue { name, value }),
+        Err(ConfigError::MissingVar { .. }) => Ok(default),
+        Err(ConfigError::EmptyVar { .. }) => Ok(default),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_env_parse_optional<T: FromStr>(
+    vars: &Vars,
+    name: &'static str,
+) -> Result<Option<T>, ConfigError> {
+    read_env_optional(vars, name)?
+        .map(|value| T::from_str(&value).map_err(|_| ConfigError::InvalidValue { name, value }))
+        .transpose()
+}
+
+/// Generates a token of `bytes` random bytes for the unset variable `name`.
+fn random_token(name: &'static str, bytes: usize) -> Result<String, ConfigError> {
+    warn!(
+        "{} is not set, using a random token which changes on every start. Set {} to keep it stable.",
+        name, name
+    );
+    Ok(random_base64(bytes)?)
+}
+
+/// Parses a byte size with an optional binary suffix, e.g. `512`, `100KiB`
+/// or `1GiB`.
+fn parse_size(value: &str) -> Option<u64> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let multiplier: u64 = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => parse_size(&text)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid size {:?}", text))),
+    }
+}
+
+/// Parses the comma separated entries of `GARAGE_BUCKETS`, recording the
+/// invalid ones in `errors`. Commas inside double quotes are taken literally.
+fn parse_buckets(raw: &str, errors: &mut Vec<ConfigError>) -> Vec<BucketConfig> {
+    let Some(entries) = split_quoted(raw, ',') else {
+        errors.push(ConfigError::InvalidBucketEntry {
+            entry: raw.to_string(),
+        });
+        return Vec::new();
+    };
+    let mut buckets = Vec::new();
+    for raw_entry in entries {
+        match parse_bucket(raw_entry) {
+            Ok(bucket) => buckets.push(bucket),
+            Err(error) => errors.push(error),
+        }
+    }
+    buckets
+}
+
+/// Parses a single `name[:policy][:option=value...]` entry of
+/// `GARAGE_BUCKETS`. Colons inside double quotes are taken literally, e.g.
+/// `error_document="errors/404:gone.html"`.
+fn parse_bucket(raw_entry: &str) -> Result<BucketConfig, ConfigError> {
+    let invalid_entry = |entry: &str| ConfigError::InvalidBucketEntry {
+        entry: entry.to_string(),
+    };
+    let entry = raw_entry.trim();
+    if entry.is_empty() {
+        return Err(invalid_entry(raw_entry));
+    }
+    let mut parts = split_quoted(entry, ':')
+        .ok_or_else(|| invalid_entry(raw_entry))?
+        .into_iter();
+    let name = unquote(parts.next().unwrap().trim());
+
+    let mut policy = BucketPolicy::Private;
+    let mut quotas = None::<BucketQuotas>;
+    let mut index_document = None;
+    let mut error_document = None;
+    let mut spa = false;
+    for (index, part) in parts.enumerate() {
+        let Some((option, value)) = part.split_once('=') else {
+            if index > 0 {
+                return Err(invalid_entry(raw_entry));
+            }
+            let part = unquote(part.trim());
+            policy =
+                BucketPolicy::from_str(&part).map_err(|_| ConfigError::InvalidBucketPolicy {
+                    bucket: name.clone(),
+                    value: part.clone(),
+                })?;
+            continue;
+        };
+        let (option, value) = (unquote(option.trim()), unquote(value.trim()));
+        let invalid_option = || ConfigError::InvalidBucketOption {
+            bucket: name.clone(),
+            option: option.clone(),
+            value: value.clone(),
+        };
+        match option.as_str() {
+            "max_size" => {
+                quotas.get_or_insert_default().max_size =
+                    Some(parse_size(&value).ok_or_else(invalid_option)?);
+            }
+            "max_objects" => {
+                quotas.get_or_insert_default().max_objects =
+                    Some(value.parse().map_err(|_| invalid_option())?);
+            }
+            "index_document" if !value.is_empty() => {
+                index_document = Some(value);
+            }
+            "error_document" if !value.is_empty() => {
+                error_document = Some(value);
+            }
+            "index_document" | "error_document" => return Err(invalid_option()),
+            "spa" => spa = parse_bool(&value).ok_or_else(invalid_option)?,
+            _ => {
+                return Err(ConfigError::UnknownBucketOption {
+                    bucket: name,
+                    option,
+                });
+            }
+        }
+    }
+
+    Ok(BucketConfig {
+        quotas,
+        index_document,
+        error_document,
+        spa,
+        ..BucketConfig::new(name, policy)
+    })
+}
+
+/// Records the error of `result` in `errors` and returns the default value
+/// instead, so the remaining variables are still checked.
+fn collect<T: Default>(errors: &mut Vec<ConfigError>, result: Result<T, ConfigError>) -> T {
+    result.unwrap_or_else(|error| {
+        errors.push(error);
+        T::default()
+    })
+}
+
+fn validate_access_key(config: &Config, errors: &mut Vec<ConfigError>) {
+    if let Err(error) = validate_access_key_id(&config.access_key_id) {
+        errors.push(error);
+    }
+    let secret_access_key = config.secret_access_key.expose();
+    if secret_access_key.len() != 64 || !secret_access_key.chars().all(|c| c.is_ascii_hexdigit()) {
+        errors.push(ConfigError::InvalidSecretAccessKey);
+    }
+}
+
+fn validate_tokens(config: &Config, errors: &mut Vec<ConfigError>) {
+    if config.metrics_token.as_ref().map(Secret::expose) == Some(config.admin_token.expose()) {
+        errors.push(ConfigError::TokenCollision);
+    }
+}
+
+/// Pruning without any configured bucket would delete every bucket, most
+/// likely because `GARAGE_BUCKETS` was forgotten.
+fn validate_prune(config: &Config, errors: &mut Vec<ConfigError>) {
+    if config.prune_buckets && config.buckets.is_empty() {
+        errors.push(ConfigError::PruneWithoutBuckets);
+    }
+}
+
+/// CORS rules and lifecycle are set through the S3 API with the configured
+/// access key, which garage only allows for owners of the bucket.
+fn validate_s3_owner(config: &Config, errors: &mut Vec<ConfigError>) {
+    for bucket in &config.buckets {
+        let owner = bucket.permissions().iter().any(|grant| {
+            grant.owner
+                && grant
+                    .key
+                    .as_ref()
+                    .is_none_or(|key| *key == config.access_key_id)
+        });
+        // The S3 API resolves a local alias in the namespace of the signing
+        // key only.
+        let foreign_key = bucket
+            .local_alias
+            .as_ref()
+            .and_then(|local_alias| local_alias.key.as_ref())
+            .filter(|key| **key != config.access_key_id);
+        for (option, set) in [
+            ("cors", bucket.cors.is_some()),
+            ("expire_after_days", bucket.expire_after_days.is_some()),
+        ] {
+            if !set {
+                continue;
+            }
+            if !owner {
+                errors.push(ConfigError::S3OptionWithoutOwner {
+                    bucket: bucket.name.clone(),
+                    option,
+                });
+            }
+            if let Some(key) = foreign_key {
+                errors.push(ConfigError::S3OptionOnForeignLocalAlias {
+                    bucket: bucket.name.clone(),
+                    option,
+                    key: key.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Checks that `value` is `GK` followed by 24 hex digits, like the access key
+/// ids garage generates.
+fn validate_access_key_id(value: &str) -> Result<(), ConfigError> {
+    let invalid = |reason| ConfigError::InvalidAccessKeyId {
+        value: value.to_string(),
+        reason,
+    };
+    let Some(digits) = value.strip_prefix("GK") else {
+        return Err(invalid("must start with GK"));
+    };
+    if digits.len() != 24 {
+        return Err(invalid("must be GK followed by 24 hex digits"));
+    }
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid("may only contain hex digits after GK"));
+    }
+    Ok(())
+}
+
+/// Checks the bucket names and settings which are not covered by parsing.
+fn validate_buckets(buckets: &[BucketConfig], errors: &mut Vec<ConfigError>) {
+    for (index, bucket) in buckets.iter().enumerate() {
+        for name in std::iter::once(&bucket.name).chain(bucket.aliases.iter().flatten()) {
+            if let Err(reason) = validate_bucket_name(name) {
+                errors.push(ConfigError::InvalidBucketName {
+                    name: name.clone(),
+                    reason,
+                });
+            }
+        }
+        if buckets[..index]
+            .iter()
+            .any(|other| other.name == bucket.name && other.local_alias == bucket.local_alias)
+        {
+            errors.push(ConfigError::DuplicateBucket {
+                name: bucket.name.clone(),
+            });
+        }
+        for (option, value) in [
+            ("index_document", &bucket.index_document),
+            ("error_document", &bucket.error_document),
+        ] {
+            if value.as_deref() == Some("") {
+                errors.push(ConfigError::InvalidBucketOption {
+                    bucket: bucket.name.clone(),
+                    option: option.to_string(),
+                    value: String::new(),
+                });
+            }
+        }
+        let permissions = bucket.permissions.as_deref().unwrap_or_default();
+        let local_alias_key = bucket
+            .local_alias
+            .as_ref()
+            .and_then(|alias| alias.key.as_ref());
+        for key in permissions
+            .iter()
+            .filter_map(|grant| grant.key.as_ref())
+            .chain(local_alias_key)
+        {
+            if let Err(e) = validate_access_key_id(key) {
+                errors.push(e);
+            }
+        }
+        for (index, grant) in permissions.iter().enumerate() {
+            if permissions[..index]
+                .iter()
+                .any(|other| other.key == grant.key)
+            {
+                errors.push(ConfigError::DuplicateBucketKey {
+                    bucket: bucket.name.clone(),
+                    key: grant
+                        .key
+                        .clone()
+                        .unwrap_or_else(|| "GARAGE_ACCESS_KEY_ID".into()),
+                });
+            }
+        }
+        if bucket.expire_after_days == Some(0) {
+            errors.push(ConfigError::InvalidBucketOption {
+                bucket: bucket.name.clone(),
+                option: "expire_after_days".into(),
+                value: "0".into(),
+            });
+        }
+        if bucket.tags.contains_key("") {
+            errors.push(ConfigError::InvalidBucketOption {
+                bucket: bucket.name.clone(),
+                option: "tags".into(),
+                value: String::new(),
+            });
+        }
+        if bucket.spa && bucket.error_document.is_some() {
+            errors.push(ConfigError::SpaWithErrorDocument {
+                bucket: bucket.name.clone(),
+            });
+        }
+        if bucket.object_lock.is_some() {
+            errors.push(ConfigError::UnsupportedBucketOption {
+                bucket: bucket.name.clone(),
+                option: "object_lock",
+            });
+        }
+        for rule in bucket.cors.iter().flatten() {
+            let invalid_rule = |reason| ConfigError::InvalidCorsRule {
+                bucket: bucket.name.clone(),
+                reason,
+            };
+            if rule.allowed_origins.is_empty() {
+                errors.push(invalid_rule("allowed_origins must not be empty"));
+            }
+            if rule.allowed_methods.is_empty() {
+                errors.push(invalid_rule("allowed_methods must not be empty"));
+            }
+            if !rule
+                .allowed_methods
+                .iter()
+                .all(|method| CORS_METHODS.contains(&method.as_str()))
+            {
+                errors.push(invalid_rule(
+                    "allowed_methods may only contain GET, PUT, POST, DELETE and HEAD",
+                ));
+            }
+        }
+    }
+}
+
+/// Splits `input` at every `separator` outside of double quotes. The quotes
+/// are kept so the parts can be split again. Returns `None` if a quote is not
+/// closed.
+fn split_quoted(input: &str, separator: char) -> Option<Vec<&str>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    parts.push(&input[start..]);
+    Some(parts)
+}
+
+/// Removes double quotes, resolving `\"` and `\\` inside of them.
+fn unquote(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut quoted = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if quoted => output.extend(chars.next()),
+            '"' => quoted = !quoted,
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+/// Checks `name` against the S3 bucket naming rules, returning the rule that
+/// was violated.
+fn validate_bucket_name(name: &str) -> Result<(), &'static str> {
+    if !(3..=63).contains(&name.len()) {
+        return Err("must be between 3 and 63 characters long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+    {
+        return Err("may only contain lowercase letters, digits, '-' and '.'");
+    }
+    let is_alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if !is_alphanumeric(name.chars().next()) || !is_alphanumeric(name.chars().last()) {
+        return Err("must start and end with a letter or digit");
+    }
+    if name.contains("..") {
+        return Err("must not contain consecutive dots");
+    }
+    if name.parse::<Ipv4Addr>().is_ok() {
+        return Err("must not be formatted as an IP address");
     }
-    chars.all(|c| c.is_ascii_alphanumeric() || c == '-')
+    Ok(())
 }
@@ -0,0 +1,140 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::admin_api::Client;
+use crate::metrics::BootstrapMetrics;
+
+/// Shared state reported by the health endpoint.
+#[derive(Default)]
+pub struct Health {
+    bootstrapped: AtomicBool,
+    api: OnceLock<Client>,
+    metrics: Mutex<Arc<BootstrapMetrics>>,
+}
+
+impl Health {
+    pub fn set_api(&self, api: Client) {
+        let _ = self.api.set(api);
+    }
+
+    /// Replaces the metrics served on `/wrapper-metrics`, e.g. with the ones
+    /// of a restarted garage.
+    pub fn set_metrics(&self, metrics: Arc<BootstrapMetrics>) {
+        *self.metrics.lock().unwrap() = metrics;
+    }
+
+    pub fn set_bootstrapped(&self, bootstrapped: bool) {
+        self.bootstrapped.store(bootstrapped, Ordering::SeqCst);
+    }
+
+    /// Garage is considered healthy once bootstrapping completed and every
+    /// node in the cluster status is up.
+    async fn is_healthy(&self) -> bool {
+        if !self.bootstrapped.load(Ordering::SeqCst) {
+            return false;
+        }
+        let Some(api) = self.api.get() else {
+            return false;
+        };
+        match api.get_cluster_status().await {
+            Ok(status) => !status.nodes.is_empty() && status.nodes.iter().all(|n| n.is_up),
+            Err(e) => {
+                debug!("Health check could not get cluster status: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Binds the health endpoint and serves it in a background task.
+pub async fn spawn_server(addr: SocketAddr, health: Arc<Health>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Health endpoint listening on {}", addr);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let health = health.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream, &health).await {
+                            debug!("Health request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Could not accept health connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle(stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    let (mut stream, request) = read_request(stream).await?;
+    let request = request
+        .as_ref()
+        .map(|(method, path)| (method.as_str(), path.as_str()));
+    let response = match request {
+        Some(("GET", "/health")) => {
+            if health.is_healthy().await {
+                response("200 OK", "text/plain", "ok\n")
+            } else {
+                response("503 Service Unavailable", "text/plain", "unavailable\n")
+            }
+        }
+        Some(("GET", "/wrapper-metrics")) => {
+            let metrics = health.metrics.lock().unwrap().clone();
+            response("200 OK", "text/plain; version=0.0.4", &metrics.render())
+        }
+        Some(("GET", _)) => not_found(),
+        _ => method_not_allowed(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+pub(crate) fn not_found() -> String {
+    response("404 Not Found", "text/plain", "not found\n")
+}
+
+pub(crate) fn method_not_allowed() -> String {
+    response(
+        "405 Method Not Allowed",
+        "text/plain",
+        "method not allowed\n",
+    )
+}
+
+/// Reads the request line and headers of a request, returning its method and
+/// path. The request body is never used.
+pub(crate) async fn read_request(
+    stream: TcpStream,
+) -> std::io::Result<(TcpStream, Option<(String, String)>)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut line = String::new();
+    while reader.read_line(&mut line).await? > 2 {
+        line.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let request = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => Some((method.to_string(), path.to_string())),
+        _ => None,
+    };
+    Ok((reader.into_inner(), request))
+}
+
+pub(crate) fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,1880 @@
+//! Starts a single-node garage server and bootstraps its layout, access key
+//! and buckets. The `garage-bootstrap` binary is a thin wrapper around this
+//! library.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{OpenOptions, Permissions, create_dir_all, read_to_string, set_permissions};
+use std::future::pending;
+use std::io::ErrorKind;
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::admin_api::Client;
+use crate::admin_api::types::{
+    AddBucketAliasRequest, ApiBucketQuotas, ApplyClusterLayoutRequest, BucketKeyPermChangeRequest,
+    CreateBucketLocalAlias, GetClusterLayoutResponse, ImportKeyRequest,
+    LocalLaunchRepairOperationRequest, LocalListWorkersRequest, NodeRoleChange,
+    RemoveBucketAliasRequest, RepairType, UpdateBucketWebsiteAccess, UpdateClusterLayoutRequest,
+};
+use crate::config::{BucketMode, CompressionLevel, ConfigMode, VersionCheck};
+use crate::metrics::BootstrapMetrics;
+use crate::notify::notify;
+use crate::random::random_hex;
+use anyhow::{Context, Result, bail};
+use futures::future::join_all;
+use futures::{TryStreamExt, stream};
+use nix::sys::signal::{Signal, kill};
+use nix::unistd::Pid;
+use reqwest::header;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, sleep, timeout};
+use toml_edit::{DocumentMut, Item, Table, value};
+use tracing::{Level, debug, error, info, trace, warn};
+
+pub mod admin_api;
+pub mod config;
+pub mod health;
+pub mod metrics;
+pub mod notify;
+pub mod random;
+pub mod s3;
+
+pub use crate::config::{
+    BucketConfig, BucketKeyPermissions, BucketPolicy, BucketQuotas, Config, ConfigBuilder,
+    ConfigError, CorsRule, LocalAlias, Secret,
+};
+
+/// Admin API client of a running garage, see [`Garage::admin`], with its
+/// error type and the request and response types most often needed by
+/// embedders. Everything else is available in [`admin_api::types`].
+pub use crate::admin_api::types::{
+    AllowBucketKeyRequest, ApiBucketKeyPerm, CreateBucketRequest, CreateBucketResponse,
+    CreateKeyRequest, CreateKeyResponse, DenyBucketKeyRequest, GetBucketInfoResponse,
+    GetClusterHealthResponse, GetClusterStatusResponse, GetKeyInfoResponse, ListBucketsResponse,
+    ListBucketsResponseItem, ListKeysResponse, ListKeysResponseItem, UpdateBucketRequestBody,
+};
+pub use crate::admin_api::{Client as AdminClient, Error as AdminError};
+
+const GARAGE_ADMIN_URL: &str = "http://127.0.0.1:3903";
+const GARAGE_S3_URL: &str = "http://127.0.0.1:3900";
+/// Used if the garage config sets no `s3_api.s3_region`, must match the
+/// bundled garage.toml.
+const GARAGE_S3_REGION: &str = "garage";
+/// Garage major version whose admin API the generated client targets.
+const SUPPORTED_GARAGE_MAJOR: u64 = 2;
+const SUPPORTED_GARAGE_VERSIONS: &str = "2.x";
+const GARAGE_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+const START_POLL_INTERVAL_MAX: Duration = Duration::from_secs(2);
+/// How long to wait for the remaining output of garage after it exited.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+const API_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+const API_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(10);
+const LAYOUT_APPLY_ATTEMPTS: u32 = 3;
+
+pub struct Garage {
+    pub process: Child,
+    pub config_path: PathBuf,
+    pub api: Client,
+    pub s3: s3::Client,
+    /// Id of the local node, read once from the cluster status while waiting
+    /// for garage to start.
+    pub node_id: NodeId,
+    pub metrics: Arc<BootstrapMetrics>,
+}
+
+impl Garage {
+    /// Admin API client authenticated with the admin token. This is the
+    /// stable way to make further admin calls, e.g. creating extra keys or
+    /// querying bucket usage.
+    pub fn admin(&self) -> &Client {
+        &self.api
+    }
+
+    /// Waits for the garage process to exit.
+    pub async fn wait(&mut self) -> std::io::Result<ExitStatus> {
+        self.process.wait().await
+    }
+
+    /// Sends SIGTERM to garage and waits up to `grace` for it to exit before
+    /// killing it.
+    pub async fn shutdown(mut self, grace: Duration) -> Result<ExitStatus> {
+        self.stop(Signal::SIGTERM, grace).await
+    }
+
+    /// Like [`Garage::shutdown`], but forwards `signal` instead of SIGTERM.
+    pub async fn stop(&mut self, signal: Signal, grace: Duration) -> Result<ExitStatus> {
+        stop_process(&mut self.process, signal, grace)
+            .await
+            .context("Could not stop garage process")
+    }
+}
+
+/// Forwards `signal` to the garage process and waits up to `grace` for it to
+/// exit before killing it.
+async fn stop_process(
+    process: &mut Child,
+    signal: Signal,
+    grace: Duration,
+) -> std::io::Result<ExitStatus> {
+    if let Some(pid) = process.id() {
+        info!(
+            "Sending {} to garage, killing it if it has not exited within {:?}...",
+            signal, grace
+        );
+        kill(Pid::from_raw(pid as i32), signal)?;
+    }
+    let started = Instant::now();
+    match timeout(grace, process.wait()).await {
+        Ok(status) => {
+            info!(
+                "Garage exited after {:?}, within the grace period",
+                started.elapsed()
+            );
+            status
+        }
+        Err(_) => {
+            warn!("Garage did not exit within {:?}, sending SIGKILL", grace);
+            process.kill().await?;
+            process.wait().await
+        }
+    }
+}
+
+pub struct NodeId(String);
+
+impl NodeId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LayoutError {
+    #[error("cluster layout request failed")]
+    Api(#[from] progenitor_client::Error),
+    #[error("could not apply the cluster layout after {attempts} attempts")]
+    AttemptsExhausted {
+        attempts: u32,
+        #[source]
+        source: progenitor_client::Error,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum StartError {
+    #[error("garage binary {path:?} does not exist")]
+    BinaryNotFound { path: PathBuf },
+    #[error("garage binary {path:?} is not executable")]
+    BinaryNotExecutable { path: PathBuf },
+    #[error("failed to build admin API client")]
+    HttpClient(#[source] reqwest::Error),
+    #[error("failed to read garage config {path:?}")]
+    ReadConfig {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid garage config {path:?}")]
+    InvalidConfig {
+        path: PathBuf,
+        #[source]
+        source: toml_edit::TomlError,
+    },
+    #[error("failed to spawn garage process")]
+    Spawn(#[source] std::io::Error),
+    #[error("garage exited before becoming available with status {0}")]
+    Exited(ExitStatus),
+    #[error(
+        "garage exited with {status} because an address is already in use, is another garage instance running?"
+    )]
+    AddressInUse { status: ExitStatus, line: String },
+    #[error(
+        "garage exited with {status} because its database is locked, is another garage instance using the metadata directory?"
+    )]
+    DatabaseLocked { status: ExitStatus, line: String },
+    #[error("garage was stopped by {signal} before becoming available and exited with {status}")]
+    Interrupted { signal: Signal, status: ExitStatus },
+    #[error("failed to stop garage process")]
+    Stop(#[source] std::io::Error),
+    #[error("timed out waiting for garage to become available after {timeout:?}")]
+    Timeout { timeout: Duration },
+    #[error("failed to check garage availability")]
+    AvailabilityCheck(#[source] std::io::Error),
+    #[error("invalid garage admin address {addr}")]
+    InvalidAdminAddr {
+        addr: String,
+        #[source]
+        source: std::net::AddrParseError,
+    },
+    #[error(
+        "garage rejected the admin token with status {status}, does admin.admin_token of the garage config match GARAGE_ADMIN_TOKEN?"
+    )]
+    AdminAuthFailed { status: reqwest::StatusCode },
+    #[error("unexpected number of nodes in status: {0}")]
+    UnexpectedNumberOfNodes(usize),
+    #[error("invalid garage cluster status {0:?}")]
+    InvalidClusterStatus(GetClusterStatusResponse),
+    #[error("garage version {found} is not supported, supported versions are {supported}")]
+    IncompatibleVersion {
+        found: String,
+        supported: &'static str,
+    },
+}
+
+/// Makes a file containing secrets readable by its owner only. This is best
+/// effort as not every filesystem supports it.
+fn restrict_permissions(path: &Path) {
+    if let Err(e) = set_permissions(path, Permissions::from_mode(0o600)) {
+        warn!("Could not restrict permissions of {:?}: {}", path, e);
+    }
+}
+
+/// Writes a file containing secrets. A new file is created readable by its
+/// owner only, an existing one is restricted after writing.
+fn write_secret_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())?;
+    restrict_permissions(path);
+    Ok(())
+}
+
+/// Returns the configured rpc_secret, the one stored in `rpc_secret_path` or
+/// a newly generated one which is only written to `rpc_secret_path` if
+/// `persist` is set.
+fn rpc_secret(config: &Config, persist: bool) -> Result<String> {
+    if let Some(secret) = &config.rpc_secret {
+        info!("Using rpc_secret provided via GARAGE_RPC_SECRET");
+        return Ok(secret.expose().to_string());
+    }
+    let path = &config.rpc_secret_path;
+    match read_to_string(path) {
+        Ok(secret) if !secret.trim().is_empty() => {
+            info!("Reusing rpc_secret from {:?}", path);
+            return Ok(secret.trim().to_string());
+        }
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Could not read rpc_secret from {:?}", path));
+        }
+    }
+    let secret = random_hex(32).context("Could not generate rpc_secret")?;
+    if !persist {
+        info!("Generated new rpc_secret without storing it");
+        return Ok(secret);
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)
+            .with_context(|| format!("Could not create directory {:?}", parent))?;
+    }
+    write_secret_file(path, &secret)
+        .with_context(|| format!("Could not write rpc_secret to {:?}", path))?;
+    info!("Generated new rpc_secret and stored it in {:?}", path);
+    Ok(secret)
+}
+
+fn merge_extra_config(
+    doc: &mut DocumentMut,
+    path: &Path,
+    managed: &[(&'static str, Option<Item>)],
+) -> Result<()> {
+    let extra = read_to_string(path)
+        .with_context(|| format!("Could not read {:?}", path))?
+        .parse::<DocumentMut>()
+        .with_context(|| format!("Could not parse {:?}", path))?;
+    for (key, _) in managed {
+        let item = key
+            .split('.')
+            .try_fold(extra.as_item(), |item, part| item.get(part));
+        if item.is_some() {
+            warn!(
+                "Ignoring {} from {:?}, it is managed by garage-bootstrap",
+                key, path
+            );
+        }
+    }
+    info!("Merging extra config from {:?}", path);
+    merge_tables(doc.as_table_mut(), extra.as_table());
+    Ok(())
+}
+
+fn merge_tables(target: &mut Table, source: &Table) {
+    for (key, item) in source.iter() {
+        match (
+            target.get_mut(key).and_then(Item::as_table_mut),
+            item.as_table(),
+        ) {
+            (Some(target_table), Some(source_table)) => merge_tables(target_table, source_table),
+            _ => {
+                target.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+/// Renders the garage config according to `config.config_mode`. Returns
+/// `None` if an existing config file is to be preserved as is. A generated
+/// rpc_secret is only persisted if `persist_rpc_secret` is set.
+pub fn render_config(config: &Config, persist_rpc_secret: bool) -> Result<Option<DocumentMut>> {
+    let path = &config.config_path;
+    let exists = path
+        .try_exists()
+        .with_context(|| format!("Could not check existence of {:?}", path))?;
+    let mut doc = match config.config_mode {
+        ConfigMode::Preserve if exists => {
+            info!("Preserving existing config {:?}", path);
+            return Ok(None);
+        }
+        ConfigMode::Merge if exists => {
+            info!("Merging managed settings into existing config {:?}", path);
+            read_to_string(path)
+                .with_context(|| format!("Could not read {:?}", path))?
+                .parse::<DocumentMut>()
+                .with_context(|| format!("Could not parse {:?}", path))?
+        }
+        _ => include_str!("garage.toml")
+            .parse::<DocumentMut>()
+            .expect("Bundled garage.toml is invalid"),
+    };
+    let managed = managed_settings(config, persist_rpc_secret)?;
+    if let Some(extra_path) = &config.config_extra_path {
+        merge_extra_config(&mut doc, extra_path, &managed)?;
+    }
+    for (key, item) in managed {
+        match (key.split_once('.'), item) {
+            (None, Some(item)) => doc[key] = item,
+            (Some((section, key)), Some(item)) => doc[section][key] = item,
+            (None, None) => {
+                doc.remove(key);
+            }
+            (Some((section, key)), None) => {
+                if let Some(table) = doc[section].as_table_like_mut() {
+                    table.remove(key);
+                }
+            }
+        }
+    }
+    Ok(Some(doc))
+}
+
+/// Config keys written by [`render_config`], as `key` or `section.key`,
+/// taking precedence over the existing and extra config. Keys whose variable
+/// is unset are not listed, `None` removes the key.
+fn managed_settings(
+    config: &Config,
+    persist_rpc_secret: bool,
+) -> Result<Vec<(&'static str, Option<Item>)>> {
+    let mut managed = vec![
+        (
+            "metadata_dir",
+            Some(value(config.metadata_dir.display().to_string())),
+        ),
+        (
+            "data_dir",
+            Some(value(config.data_dir.display().to_string())),
+        ),
+        ("db_engine", Some(value(config.db_engine.to_string()))),
+    ];
+    match config.compression_level {
+        Some(CompressionLevel::None) => managed.push(("compression_level", Some(value("none")))),
+        Some(CompressionLevel::Level(level)) => {
+            managed.push(("compression_level", Some(value(i64::from(level)))))
+        }
+        None => (),
+    }
+    if let Some(block_size) = config.block_size {
+        managed.push((
+            "block_size",
+            Some(value(i64::try_from(block_size).unwrap_or(i64::MAX))),
+        ));
+    }
+    managed.push((
+        "replication_factor",
+        Some(value(
+            i64::try_from(config.replication_factor).unwrap_or(i64::MAX),
+        )),
+    ));
+    managed.push((
+        "rpc_secret",
+        Some(value(rpc_secret(config, persist_rpc_secret)?)),
+    ));
+    for (key, bind) in [
+        ("s3_api.api_bind_addr", config.s3_api_bind),
+        ("s3_web.bind_addr", config.web_bind),
+        ("admin.api_bind_addr", config.admin_bind),
+    ] {
+        if let Some(bind) = bind {
+            managed.push((key, Some(value(bind.to_string()))));
+        }
+    }
+    for (key, root_domain) in [
+        ("s3_api.root_domain", &config.s3_root_domain),
+        ("s3_web.root_domain", &config.web_root_domain),
+    ] {
+        if let Some(root_domain) = root_domain {
+            managed.push((key, Some(value(root_domain))));
+        }
+    }
+    managed.push((
+        "admin.admin_token",
+        Some(value(config.admin_token.expose())),
+    ));
+    managed.push((
+        "admin.metrics_token",
+        config
+            .metrics_token
+            .as_ref()
+            .map(|token| value(token.expose())),
+    ));
+    Ok(managed)
+}
+
+/// Writes the garage config to `config_path`. A dry run writes it to
+/// [`garage_config_path`] instead and stores no generated rpc_secret, leaving
+/// the files of a real run untouched.
+pub fn create_config(config: &Config) -> Result<()> {
+    if config.dry_run {
+        let doc = match render_config(config, false)? {
+            Some(doc) => doc.to_string(),
+            None => read_to_string(&config.config_path)
+                .with_context(|| format!("Could not read {:?}", config.config_path))?,
+        };
+        let path = garage_config_path(config);
+        info!("Dry run: writing garage config to {:?}", path);
+        return write_secret_file(&path, &doc)
+            .with_context(|| format!("Could not write {:?}", path));
+    }
+    let Some(doc) = render_config(config, true)? else {
+        return Ok(());
+    };
+    let path = &config.config_path;
+    write_secret_file(path, &doc.to_string()).with_context(|| {
+        format!(
+            "Could not write {:?}, set GARAGE_CONFIG_PATH to a writable location",
+            path
+        )
+    })?;
+    Ok(())
+}
+
+/// Config file garage is started with, a temporary one for dry runs.
+pub fn garage_config_path(config: &Config) -> PathBuf {
+    if config.dry_run {
+        env::temp_dir().join(format!("garage-bootstrap-dry-run-{}.toml", process::id()))
+    } else {
+        config.config_path.clone()
+    }
+}
+
+/// Compares the version reported by garage with the one the admin client
+/// was generated for, see `GARAGE_VERSION_CHECK`.
+fn check_version(version: Option<&str>, config: &Config) -> Result<(), StartError> {
+    if config.version_check == VersionCheck::Off {
+        return Ok(());
+    }
+    let Some(version) = version else {
+        warn!("Garage did not report its version, skipping version check");
+        return Ok(());
+    };
+    let major = version
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|major| major.parse::<u64>().ok());
+    if major == Some(SUPPORTED_GARAGE_MAJOR) {
+        debug!("Garage version {} is supported", version);
+        return Ok(());
+    }
+    if config.version_check == VersionCheck::Error {
+        return Err(StartError::IncompatibleVersion {
+            found: version.to_string(),
+            supported: SUPPORTED_GARAGE_VERSIONS,
+        });
+    }
+    warn!(
+        "Garage version {} is not supported, supported versions are {}",
+        version, SUPPORTED_GARAGE_VERSIONS
+    );
+    Ok(())
+}
+
+async fn wait_for_garage(
+    child: &mut Child,
+    output: Vec<JoinHandle<()>>,
+    conflict: &OnceLock<(StartConflict, String)>,
+    admin_api: &Client,
+    config: &Config,
+) -> Result<NodeId, StartError> {
+    let start = Instant::now();
+    let mut next_log = Some(config.start_log_interval);
+    let mut poll_interval = config.start_poll_interval;
+    let mut unexpected_nodes = None;
+    loop {
+        if let Some(status) = child.try_wait().map_err(StartError::AvailabilityCheck)? {
+            error!("Garage exited after {:.1}s", start.elapsed().as_secs_f64());
+            // The reason is usually in the last lines of output.
+            let _ = timeout(OUTPUT_DRAIN_TIMEOUT, join_all(output)).await;
+            return Err(match conflict.get() {
+                Some((kind, line)) => kind.into_error(status, line.clone()),
+                None => StartError::Exited(status),
+            });
+        }
+        match admin_api.get_cluster_status().await {
+            Ok(status) if status.nodes.len() != 1 => {
+                unexpected_nodes = Some(status.nodes.len());
+            }
+            Ok(status) if status.nodes[0].is_up => {
+                info!("Garage ready after {:.1}s", start.elapsed().as_secs_f64());
+                check_version(status.nodes[0].garage_version.as_deref(), config)?;
+                return Ok(NodeId(status.nodes[0].id.clone()));
+            }
+            Ok(_) => unexpected_nodes = None,
+            // Retrying cannot help if the token is wrong, e.g. a preserved
+            // config with a different admin_token.
+            Err(err)
+                if matches!(
+                    err.status(),
+                    Some(reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN)
+                ) =>
+            {
+                error!("Garage rejected the admin token");
+                return Err(StartError::AdminAuthFailed {
+                    status: err.status().unwrap(),
+                });
+            }
+            Err(_) => (),
+        };
+        if let Some(log_at) = next_log.filter(|log_at| start.elapsed() > *log_at) {
+            next_log = (!config.start_log_once).then(|| log_at + config.start_log_interval);
+            info!(
+                "Waiting for garage... ({:.1}s)",
+                start.elapsed().as_secs_f64()
+            );
+            notify(&format!(
+                "STATUS=Waiting for garage... ({:.1}s)",
+                start.elapsed().as_secs_f64()
+            ));
+        }
+        if start.elapsed() >= config.start_timeout {
+            error!(
+                "Garage not ready after {:.1}s",
+                start.elapsed().as_secs_f64()
+            );
+            if let Some(count) = unexpected_nodes {
+                return Err(StartError::UnexpectedNumberOfNodes(count));
+            }
+            return Err(StartError::Timeout {
+                timeout: config.start_timeout,
+            });
+        }
+        // Poll quickly at first, then back off so slow starts cause fewer
+        // requests, without sleeping past the timeout.
+        sleep(poll_interval.min(config.start_timeout.saturating_sub(start.elapsed()))).await;
+        poll_interval =
+            (poll_interval * 2).min(START_POLL_INTERVAL_MAX.max(config.start_poll_interval));
+    }
+}
+
+/// Known reasons for garage to exit right after starting, recognized in
+/// its output.
+#[derive(Debug, Clone, Copy)]
+enum StartConflict {
+    AddressInUse,
+    DatabaseLocked,
+}
+
+impl StartConflict {
+    fn detect(line: &str) -> Option<Self> {
+        let line = line.to_ascii_lowercase();
+        if line.contains("address already in use") || line.contains("addrinuse") {
+            Some(Self::AddressInUse)
+        } else if line.contains("database is locked") {
+            Some(Self::DatabaseLocked)
+        } else {
+            None
+        }
+    }
+
+    fn into_error(self, status: ExitStatus, line: String) -> StartError {
+        match self {
+            Self::AddressInUse => StartError::AddressInUse { status, line },
+            Self::DatabaseLocked => StartError::DatabaseLocked { status, line },
+        }
+    }
+}
+
+/// Re-emits every line written by garage as a tracing event with target
+/// `garage`. Lines carrying a log level of their own keep it, all other lines
+/// are logged at `default_level`. The first line indicating a
+/// [`StartConflict`] is recorded in `conflict`.
+fn forward_output<R: AsyncRead + Unpin + Send + 'static>(
+    reader: R,
+    default_level: Level,
+    conflict: Arc<OnceLock<(StartConflict, String)>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if conflict.get().is_none()
+                && let Some(kind) = StartConflict::detect(&line)
+            {
+                let _ = conflict.set((kind, line.clone()));
+            }
+            let level = line_level(&line).unwrap_or(default_level);
+            match level {
+                Level::ERROR => error!(target: "garage", "{}", line),
+                Level::WARN => warn!(target: "garage", "{}", line),
+                Level::INFO => info!(target: "garage", "{}", line),
+                Level::DEBUG => debug!(target: "garage", "{}", line),
+                Level::TRACE => trace!(target: "garage", "{}", line),
+            }
+        }
+    })
+}
+
+/// Log level of a garage log line like `2024-01-01T00:00:00Z  INFO garage: ...`,
+/// found by name in its first two words once colors are removed. Unlike
+/// parsing a [`Level`], numbers are not taken as levels.
+fn line_level(line: &str) -> Option<Level> {
+    strip_ansi(line)
+        .split_whitespace()
+        .take(2)
+        .find_map(|word| {
+            [
+                Level::ERROR,
+                Level::WARN,
+                Level::INFO,
+                Level::DEBUG,
+                Level::TRACE,
+            ]
+            .into_iter()
+            .find(|level| word.eq_ignore_ascii_case(level.as_str()))
+        })
+}
+
+/// Removes ANSI escape sequences such as colors from `line`.
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        // A CSI sequence ends with a byte in 0x40..=0x7e, other escapes
+        // consist of a single character.
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
+
+/// Whether `err` is a connection problem or timeout worth retrying, as
+/// opposed to an error response of garage.
+fn is_transient(err: &progenitor_client::Error) -> bool {
+    match err {
+        progenitor_client::Error::CommunicationError(e) => {
+            e.is_connect() || e.is_timeout() || e.is_request()
+        }
+        _ => false,
+    }
+}
+
+/// Runs the idempotent admin API request `f`, retrying it up to
+/// `config.api_retries` times with exponential backoff on transient errors.
+async fn retry<T, F, Fut>(config: &Config, mut f: F) -> Result<T, progenitor_client::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, progenitor_client::Error>>,
+{
+    let mut backoff = API_RETRY_BACKOFF;
+    let mut retries = 0;
+    loop {
+        match f().await {
+            Err(err) if retries < config.api_retries && is_transient(&err) => {
+                retries += 1;
+                warn!(
+                    "Admin API request failed, retrying in {:?} ({}/{}): {}",
+                    backoff, retries, config.api_retries, err
+                );
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(API_RETRY_BACKOFF_MAX);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// URL to reach an API bound to `bind` from this host, `default` if the bind
+/// address of the template is used.
+fn local_url(bind: Option<SocketAddr>, default: &str) -> String {
+    match bind {
+        None => default.to_string(),
+        Some(addr) if addr.ip().is_unspecified() => format!("http://127.0.0.1:{}", addr.port()),
+        Some(addr) => format!("http://{}", addr),
+    }
+}
+
+/// URL and region of the S3 API garage serves according to the config at
+/// `path`. They can differ from the defaults through a preserved, merged or
+/// extra config.
+fn s3_endpoint(path: &Path) -> Result<(String, String), StartError> {
+    let doc = read_to_string(path)
+        .map_err(|source| StartError::ReadConfig {
+            path: path.to_path_buf(),
+            source,
+        })?
+        .parse::<DocumentMut>()
+        .map_err(|source| StartError::InvalidConfig {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    let s3_api = doc.get("s3_api");
+    let region = s3_api
+        .and_then(|s3_api| s3_api.get("s3_region"))
+        .and_then(Item::as_str)
+        .unwrap_or(GARAGE_S3_REGION);
+    let bind = s3_api
+        .and_then(|s3_api| s3_api.get("api_bind_addr"))
+        .and_then(Item::as_str);
+    let addr = match bind.map(str::parse::<SocketAddr>) {
+        None => None,
+        Some(Ok(addr)) => Some(addr),
+        Some(Err(_)) => {
+            warn!(
+                "Cannot reach s3_api.api_bind_addr {:?} of {:?}, using {} for CORS rules and lifecycle",
+                bind, path, GARAGE_S3_URL
+            );
+            None
+        }
+    };
+    Ok((local_url(addr, GARAGE_S3_URL), region.to_string()))
+}
+
+/// URL of garage's admin API.
+pub(crate) fn admin_url(config: &Config) -> String {
+    match &config.admin_url {
+        Some(url) => url.clone(),
+        None => local_url(config.admin_bind, GARAGE_ADMIN_URL),
+    }
+}
+
+/// Adds the root certificates trusted for the admin API to `builder`.
+pub(crate) fn with_admin_ca_certs(
+    config: &Config,
+    builder: reqwest::ClientBuilder,
+) -> reqwest::ClientBuilder {
+    config.admin_ca_certs.iter().fold(builder, |builder, cert| {
+        builder.add_root_certificate(cert.clone())
+    })
+}
+
+fn admin_client(
+    config: &Config,
+    timeout: Duration,
+    builder: reqwest::ClientBuilder,
+) -> Result<Client, StartError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        format!("Bearer {}", config.admin_token.expose())
+            .parse()
+            .unwrap(),
+    );
+    let http = with_admin_ca_certs(config, builder)
+        .connect_timeout(Duration::from_secs(1))
+        .timeout(timeout)
+        .default_headers(headers)
+        .build()
+        .map_err(StartError::HttpClient)?;
+    Ok(admin_api::Client::new_with_client(&admin_url(config), http))
+}
+
+/// Checks that `path` is an executable file, so a wrong path is reported as
+/// such instead of a bare "No such file or directory".
+fn check_binary(path: &Path) -> Result<(), StartError> {
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(StartError::BinaryNotFound {
+                path: path.to_path_buf(),
+            });
+        }
+        Err(e) => return Err(StartError::Spawn(e)),
+    };
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        return Err(StartError::BinaryNotExecutable {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+/// Runs `garage --version` and returns its output.
+pub async fn garage_version(binary: &Path) -> Result<String> {
+    check_binary(binary)?;
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .await
+        .context("Could not run garage")?;
+    if !output.status.success() {
+        bail!("garage --version exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+pub async fn run_garage(config: &Config) -> Result<Garage, StartError> {
+    run_garage_with_client(config, reqwest::Client::builder).await
+}
+
+/// Like [`run_garage`], but builds the admin API clients from `http_client`,
+/// e.g. to configure a proxy or additional root certificates. Timeouts and
+/// the admin token are added to every builder it returns.
+pub async fn run_garage_with_client(
+    config: &Config,
+    http_client: impl Fn() -> reqwest::ClientBuilder,
+) -> Result<Garage, StartError> {
+    start_garage(config, http_client, pending()).await
+}
+
+/// Like [`run_garage`], but stops garage if `shutdown` completes before it
+/// became available. The signal it returns is forwarded to garage, which is
+/// killed if it does not exit within the shutdown grace period, and
+/// [`StartError::Interrupted`] is returned.
+pub async fn run_garage_until(
+    config: &Config,
+    shutdown: impl Future<Output = Signal>,
+) -> Result<Garage, StartError> {
+    start_garage(config, reqwest::Client::builder, shutdown).await
+}
+
+async fn start_garage(
+    config: &Config,
+    http_client: impl Fn() -> reqwest::ClientBuilder,
+    shutdown: impl Future<Output = Signal>,
+) -> Result<Garage, StartError> {
+    info!("Starting garage...");
+    let start = Instant::now();
+    let config_path = garage_config_path(config);
+    check_binary(&config.garage_binary)?;
+    let (s3_url, s3_region) = s3_endpoint(&config_path)?;
+    // Polling uses a short timeout so an unresponsive garage is retried
+    // quickly, bootstrap operations may take longer on a busy node.
+    let wait_client = admin_client(config, GARAGE_WAIT_TIMEOUT, http_client())?;
+    let api = admin_client(config, config.admin_timeout, http_client())?;
+    let mut command = Command::new(&config.garage_binary);
+    command
+        .arg("-c")
+        .arg(&config_path)
+        .arg("server")
+        .args(&config.extra_args);
+    info!(
+        "Running {}",
+        shell_words::join(
+            std::iter::once(command.as_std().get_program())
+                .chain(command.as_std().get_args())
+                .map(|arg| arg.to_string_lossy())
+        )
+    );
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(StartError::Spawn)?;
+    let conflict = Arc::new(OnceLock::new());
+    let mut output = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        output.push(forward_output(stdout, Level::INFO, conflict.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        output.push(forward_output(stderr, Level::WARN, conflict.clone()));
+    }
+    let node_id = tokio::select! {
+        node_id = wait_for_garage(&mut child, output, &conflict, &wait_client, config) => node_id?,
+        signal = shutdown => {
+            let status = stop_process(&mut child, signal, config.shutdown_grace)
+                .await
+                .map_err(StartError::Stop)?;
+            return Err(StartError::Interrupted { signal, status });
+        }
+    };
+    let metrics = Arc::new(BootstrapMetrics::new(&config.buckets));
+    metrics.set_startup(start.elapsed());
+    Ok(Garage {
+        process: child,
+        config_path,
+        api,
+        s3: s3::Client::new(
+            &s3_url,
+            &s3_region,
+            &config.access_key_id,
+            &config.secret_access_key,
+        ),
+        node_id,
+        metrics,
+    })
+}
+
+/// Makes sure the node is part of the layout and returns the layout version.
+pub async fn ensure_layout(garage: &Garage, config: &Config) -> Result<i64, LayoutError> {
+    let mut attempt = 1;
+    loop {
+        let layout = retry(config, || garage.api.get_cluster_layout()).await?;
+        if layout
+            .roles
+            .iter()
+            .any(|role| role.id == garage.node_id.as_str())
+        {
+            info!(
+                "Node {:?} is part of layout version {}, skipping initialization",
+                garage.node_id.as_str(),
+                layout.version
+            );
+            return Ok(layout.version);
+        }
+        match update_layout(garage, config, &layout).await {
+            Ok(version) => {
+                info!(
+                    "Node {:?} is part of layout version {}",
+                    garage.node_id.as_str(),
+                    version
+                );
+                return Ok(version);
+            }
+            // Garage rejects applying a version that is not the next one, but
+            // also invalid roles with the same status. Only the first is
+            // retried, recognized by the layout version having moved.
+            Err(err)
+                if matches!(
+                    err.status(),
+                    Some(reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::CONFLICT)
+                ) =>
+            {
+                let current = retry(config, || garage.api.get_cluster_layout()).await?;
+                if current.version == layout.version {
+                    return Err(err.into());
+                }
+                if attempt >= LAYOUT_APPLY_ATTEMPTS {
+                    return Err(LayoutError::AttemptsExhausted {
+                        attempts: attempt,
+                        source: err,
+                    });
+                }
+                warn!(
+                    "Applying layout failed, it was changed concurrently from version {} to {}: {} (attempt {}/{})",
+                    layout.version, current.version, err, attempt, LAYOUT_APPLY_ATTEMPTS
+                );
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Stages the current node, replacing any other node in `layout`, and applies
+/// the next layout version, which is returned.
+async fn update_layout(
+    garage: &Garage,
+    config: &Config,
+    layout: &GetClusterLayoutResponse,
+) -> Result<i64, progenitor_client::Error> {
+    let node_id = garage.node_id.as_str();
+    // Keep zone, capacity and tags if the node replaces a single previous one.
+    let (zone, capacity, tags) = match layout.roles.as_slice() {
+        [previous] => (
+            previous.zone.clone(),
+            previous.capacity,
+            previous.tags.clone(),
+        ),
+        _ => (
+            config.zone.clone(),
+            Some(i64::try_from(config.node_capacity).unwrap_or(i64::MAX)),
+            config.node_tags.clone(),
+        ),
+    };
+    let mut roles = vec![NodeRoleChange::Variant1 {
+        capacity,
+        tags,
+        zone,
+        id: node_id.to_string(),
+    }];
+    roles.extend(layout.roles.iter().map(|role| NodeRoleChange::Variant0 {
+        id: role.id.clone(),
+        remove: true,
+    }));
+    if layout.roles.is_empty() {
+        info!("No layout found. Updating cluster...");
+    } else {
+        let previous = layout
+            .roles
+            .iter()
+            .map(|role| role.id.as_str())
+            .collect::<Vec<_>>();
+        warn!(
+            "Node {:?} is not part of layout version {}, its id changed. Replacing {:?} with it...",
+            node_id, layout.version, previous
+        );
+    }
+    if config.dry_run {
+        info!(
+            "Dry run: would assign node {:?} to the layout and apply it",
+            node_id
+        );
+        return Ok(layout.version);
+    }
+    let layout = garage
+        .api
+        .update_cluster_layout(&UpdateClusterLayoutRequest {
+            parameters: None,
+            roles,
+        })
+        .await?;
+    info!("Layout updated. Applying layout...");
+    let version = layout.version + 1;
+    garage
+        .api
+        .apply_cluster_layout(&ApplyClusterLayoutRequest { version })
+        .await?;
+    info!("Layout applied.");
+    garage.metrics.layout_initialized();
+    Ok(version)
+}
+
+/// Deletes all access keys except the configured one via the admin API.
+///
+/// The configured key is kept rather than deleted and imported again,
+/// as garage keeps deleted keys around and refuses to import them again.
+pub async fn reset_keys(garage: &Garage, config: &Config) -> Result<(), progenitor_client::Error> {
+    let mut keys = retry(config, || garage.api.list_keys())
+        .await?
+        .into_inner()
+        .0;
+    keys.retain(|key| key.id != config.access_key_id);
+    if config.dry_run {
+        for key in keys.iter() {
+            info!("Dry run: would delete access key {:?}", key.id);
+        }
+        return Ok(());
+    }
+    warn!(
+        "GARAGE_RESET_KEYS is enabled, deleting {} access keys...",
+        keys.len()
+    );
+    for key in keys.iter() {
+        info!("Deleting access key {:?}", key.id);
+        garage.api.delete_key(&key.id).await?;
+    }
+    warn!("Other access keys removed: {}", keys.len());
+    Ok(())
+}
+
+pub async fn ensure_key(garage: &Garage, config: &Config) -> Result<(), progenitor_client::Error> {
+    match retry(config, || {
+        garage
+            .api
+            .get_key_info(Some(&config.access_key_id), None, Some(true))
+    })
+    .await
+    {
+        Ok(key) => {
+            if key.secret_access_key.as_deref() != Some(config.secret_access_key.expose()) {
+                warn!(
+                    "Access key {:?} already exists with a different secret access key",
+                    config.access_key_id
+                );
+            }
+            info!("Access key {:?} already present", config.access_key_id);
+            return Ok(());
+        }
+        Err(err) if err.status() == Some(reqwest::StatusCode::NOT_FOUND) => (),
+        Err(err) => return Err(err),
+    }
+    if config.dry_run {
+        info!(
+            "Dry run: would import access key {:?}",
+            config.access_key_id
+        );
+        return Ok(());
+    }
+    info!("Importing access key {:?}...", config.access_key_id);
+    garage
+        .api
+        .import_key(&ImportKeyRequest {
+            name: None,
+            access_key_id: config.access_key_id.clone(),
+            secret_access_key: config.secret_access_key.expose().to_string(),
+        })
+        .await?;
+    info!("Access key {:?} imported", config.access_key_id);
+    garage.metrics.key_imported();
+    Ok(())
+}
+
+/// Name a bucket is addressed by, either globally or in the namespace of an
+/// access key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketAlias {
+    Global(String),
+    Local {
+        access_key_id: String,
+        alias: String,
+    },
+}
+
+impl BucketAlias {
+    fn of(config: &Config, bucket_config: &BucketConfig) -> Self {
+        match &bucket_config.local_alias {
+            None => Self::Global(bucket_config.name.clone()),
+            Some(local_alias) => Self::Local {
+                access_key_id: local_alias
+                    .key
+                    .clone()
+                    .unwrap_or(config.access_key_id.clone()),
+                alias: bucket_config.name.clone(),
+            },
+        }
+    }
+}
+
+pub async fn ensure_buckets(garage: &Garage, config: &Config) -> Result<()> {
+    let mut garage_bucket_map = HashMap::<BucketAlias, String>::new();
+    // ListBuckets has no pagination parameters, garage always returns every
+    // bucket of the cluster in a single response.
+    for bucket in &retry(config, || garage.api.list_buckets()).await?.0 {
+        if bucket.global_aliases.is_empty() && bucket.local_aliases.is_empty() {
+            warn!("Ignoring bucket without an alias: {:?}", bucket);
+            continue;
+        }
+        for alias in &bucket.global_aliases {
+            garage_bucket_map.insert(BucketAlias::Global(alias.clone()), bucket.id.clone());
+        }
+        for local_alias in &bucket.local_aliases {
+            garage_bucket_map.insert(
+                BucketAlias::Local {
+                    access_key_id: local_alias.access_key_id.clone(),
+                    alias: local_alias.alias.clone(),
+                },
+                bucket.id.clone(),
+            );
+        }
+    }
+    stream::iter(config.buckets.iter().map(Ok))
+        .try_for_each_concurrent(config.bucket_concurrency, |bucket_config| {
+            let existing_id = garage_bucket_map.get(&BucketAlias::of(config, bucket_config));
+            async move {
+                ensure_bucket(garage, config, bucket_config, existing_id)
+                    .await
+                    .with_context(|| format!("Could not reconcile bucket {:?}", bucket_config.name))
+            }
+        })
+        .await?;
+    if config.prune_buckets {
+        prune_buckets(garage, config, &garage_bucket_map).await?;
+    }
+    Ok(())
+}
+
+async fn ensure_bucket(
+    garage: &Garage,
+    config: &Config,
+    bucket_config: &BucketConfig,
+    existing_id: Option<&String>,
+) -> Result<()> {
+    let bucket = match existing_id {
+        None if config.dry_run => {
+            info!(
+                "Dry run: would create bucket {:?} with policy {:?} and grant access to {:?}",
+                bucket_config.name, bucket_config.policy, config.access_key_id
+            );
+            return Ok(());
+        }
+        None => {
+            info!("Creating bucket {:?}...", bucket_config.name);
+            let request = match BucketAlias::of(config, bucket_config) {
+                BucketAlias::Global(alias) => CreateBucketRequest {
+                    global_alias: Some(alias),
+                    local_alias: None,
+                },
+                BucketAlias::Local {
+                    access_key_id,
+                    alias,
+                } => CreateBucketRequest {
+                    global_alias: None,
+                    local_alias: Some(CreateBucketLocalAlias {
+                        access_key_id,
+                        alias,
+                        allow: None,
+                    }),
+                },
+            };
+            let bucket = garage.api.create_bucket(&request).await?.into_inner().0;
+            info!("Bucket {:?} created", bucket_config.name);
+            garage.metrics.bucket_created();
+            bucket
+        }
+        Some(bucket_id) => {
+            info!(
+                "Bucket {:?} found with id {:?}",
+                bucket_config.name, bucket_id
+            );
+            garage.metrics.bucket_found();
+            if config.bucket_mode == BucketMode::CreateOnly {
+                info!(
+                    "Leaving existing bucket {:?} untouched, GARAGE_BUCKET_MODE is create-only",
+                    bucket_config.name
+                );
+                return Ok(());
+            }
+            retry(config, || {
+                garage.api.get_bucket_info(None, Some(bucket_id), None)
+            })
+            .await?
+            .into_inner()
+        }
+    };
+    let update = UpdateBucketRequestBody {
+        quotas: bucket_config.quotas.as_ref().map(|quotas| ApiBucketQuotas {
+            max_size: quotas
+                .max_size
+                .map(|v| i64::try_from(v).unwrap_or(i64::MAX)),
+            max_objects: quotas
+                .max_objects
+                .map(|v| i64::try_from(v).unwrap_or(i64::MAX)),
+        }),
+        website_access: Some(match bucket_config.policy {
+            BucketPolicy::Private => UpdateBucketWebsiteAccess {
+                enabled: false,
+                error_document: None,
+                index_document: None,
+            },
+            BucketPolicy::Public => {
+                let index_document = bucket_config
+                    .index_document
+                    .clone()
+                    .unwrap_or_else(|| "index.html".into());
+                UpdateBucketWebsiteAccess {
+                    enabled: true,
+                    error_document: if bucket_config.spa {
+                        Some(index_document.clone())
+                    } else {
+                        bucket_config.error_document.clone()
+                    },
+                    index_document: Some(index_document),
+                }
+            }
+        }),
+    };
+    if bucket_is_up_to_date(&bucket, &update) {
+        info!("Bucket {:?} already up to date", bucket_config.name);
+    } else if config.dry_run {
+        info!(
+            "Dry run: would update bucket {:?} with {:?}",
+            bucket_config.name, update
+        );
+    } else {
+        info!("Updating bucket {:?}", bucket_config.name);
+        retry(config, || garage.api.update_bucket(&bucket.id, &update)).await?;
+    }
+    if let Some(aliases) = &bucket_config.aliases {
+        ensure_global_aliases(garage, config, bucket_config, &bucket, aliases).await?;
+    }
+    let mut desired = bucket_config
+        .permissions()
+        .into_iter()
+        .map(|grant| {
+            let access_key_id = grant.key.clone().unwrap_or(config.access_key_id.clone());
+            (access_key_id, KeyPermissions::from(&grant))
+        })
+        .collect::<Vec<_>>();
+    // An explicit list of permissions is authoritative, keys which are not
+    // listed lose their access.
+    if bucket_config.permissions.is_some() {
+        for key in &bucket.keys {
+            if !desired.iter().any(|(id, _)| id == &key.access_key_id) {
+                desired.push((key.access_key_id.clone(), KeyPermissions::default()));
+            }
+        }
+    }
+    for (access_key_id, permissions) in desired {
+        let current = bucket
+            .keys
+            .iter()
+            .find(|key| key.access_key_id == access_key_id)
+            .map(|key| KeyPermissions::from(&key.permissions))
+            .unwrap_or_default();
+        if current == permissions {
+            continue;
+        }
+        if config.dry_run {
+            info!(
+                "Dry run: would change permissions of {:?} on bucket {:?} from {:?} to {:?}",
+                access_key_id, bucket_config.name, current, permissions
+            );
+            continue;
+        }
+        info!(
+            "Changing permissions of {:?} on bucket {:?} from {:?} to {:?}",
+            access_key_id, bucket_config.name, current, permissions
+        );
+        set_bucket_permissions(
+            garage,
+            config,
+            &bucket.id,
+            &access_key_id,
+            current,
+            permissions,
+        )
+        .await?;
+    }
+    if config.dry_run {
+        if let Some(cors) = &bucket_config.cors {
+            info!(
+                "Dry run: would set CORS rules of bucket {:?} to {:?}",
+                bucket_config.name, cors
+            );
+        }
+        if let Some(days) = bucket_config.expire_after_days {
+            info!(
+                "Dry run: would expire objects in bucket {:?} after {} days",
+                bucket_config.name, days
+            );
+        }
+        return Ok(());
+    }
+    if let Some(cors) = &bucket_config.cors {
+        info!(
+            "Setting {} CORS rules for bucket {:?}",
+            cors.len(),
+            bucket_config.name
+        );
+        garage
+            .s3
+            .put_bucket_cors(&bucket_config.name, cors)
+            .await
+            .context("Could not set CORS rules")?;
+    }
+    if let Some(days) = bucket_config.expire_after_days {
+        info!(
+            "Expiring objects in bucket {:?} after {} days",
+            bucket_config.name, days
+        );
+        garage
+            .s3
+            .put_bucket_lifecycle(&bucket_config.name, days)
+            .await
+            .context("Could not set lifecycle configuration")?;
+    }
+    Ok(())
+}
+
+/// Adds and removes global aliases until the bucket is reachable by exactly
+/// its name and `aliases`.
+async fn ensure_global_aliases(
+    garage: &Garage,
+    config: &Config,
+    bucket_config: &BucketConfig,
+    bucket: &GetBucketInfoResponse,
+    aliases: &[String],
+) -> Result<(), progenitor_client::Error> {
+    let mut desired = aliases.to_vec();
+    if let BucketAlias::Global(name) = BucketAlias::of(config, bucket_config) {
+        desired.push(name);
+    }
+    for alias in desired
+        .iter()
+        .filter(|alias| !bucket.global_aliases.contains(alias))
+    {
+        if config.dry_run {
+            info!(
+                "Dry run: would add global alias {:?} to bucket {:?}",
+                alias, bucket_config.name
+            );
+            continue;
+        }
+        info!(
+            "Adding global alias {:?} to bucket {:?}",
+            alias, bucket_config.name
+        );
+        garage
+            .api
+            .add_bucket_alias(&AddBucketAliasRequest::Variant0 {
+                bucket_id: bucket.id.clone(),
+                global_alias: alias.clone(),
+            })
+            .await?;
+    }
+    for alias in bucket
+        .global_aliases
+        .iter()
+        .filter(|alias| !desired.contains(alias))
+    {
+        if config.dry_run {
+            info!(
+                "Dry run: would remove global alias {:?} from bucket {:?}",
+                alias, bucket_config.name
+            );
+            continue;
+        }
+        info!(
+            "Removing global alias {:?} from bucket {:?}",
+            alias, bucket_config.name
+        );
+        garage
+            .api
+            .remove_bucket_alias(&RemoveBucketAliasRequest::Variant0 {
+                bucket_id: bucket.id.clone(),
+                global_alias: alias.clone(),
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Permissions of an access key on a bucket, with unset flags as `false`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct KeyPermissions {
+    owner: bool,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPermissions {
+    /// Flags set in `self` but not in `other`.
+    fn without(self, other: Self) -> Self {
+        Self {
+            owner: self.owner && !other.owner,
+            read: self.read && !other.read,
+            write: self.write && !other.write,
+        }
+    }
+
+    fn is_empty(self) -> bool {
+        self == Self::default()
+    }
+}
+
+impl From<&BucketKeyPermissions> for KeyPermissions {
+    fn from(grant: &BucketKeyPermissions) -> Self {
+        Self {
+            owner: grant.owner,
+            read: grant.read,
+            write: grant.write,
+        }
+    }
+}
+
+impl From<&ApiBucketKeyPerm> for KeyPermissions {
+    fn from(perm: &ApiBucketKeyPerm) -> Self {
+        Self {
+            owner: perm.owner.unwrap_or(false),
+            read: perm.read.unwrap_or(false),
+            write: perm.write.unwrap_or(false),
+        }
+    }
+}
+
+/// Allows the permissions missing from `current` and denies those which are
+/// not `desired`.
+async fn set_bucket_permissions(
+    garage: &Garage,
+    config: &Config,
+    bucket_id: &str,
+    access_key_id: &str,
+    current: KeyPermissions,
+    desired: KeyPermissions,
+) -> Result<(), progenitor_client::Error> {
+    let request = |change: KeyPermissions| BucketKeyPermChangeRequest {
+        access_key_id: access_key_id.to_string(),
+        bucket_id: bucket_id.to_string(),
+        permissions: ApiBucketKeyPerm {
+            owner: Some(change.owner),
+            read: Some(change.read),
+            write: Some(change.write),
+        },
+    };
+    let allow = desired.without(current);
+    if !allow.is_empty() {
+        let request = AllowBucketKeyRequest(request(allow));
+        retry(config, || garage.api.allow_bucket_key(&request)).await?;
+    }
+    let deny = current.without(desired);
+    if !deny.is_empty() {
+        let request = DenyBucketKeyRequest(request(deny));
+        retry(config, || garage.api.deny_bucket_key(&request)).await?;
+    }
+    Ok(())
+}
+
+fn bucket_is_up_to_date(bucket: &GetBucketInfoResponse, update: &UpdateBucketRequestBody) -> bool {
+    if let Some(quotas) = &update.quotas
+        && (bucket.quotas.max_size != quotas.max_size
+            || bucket.quotas.max_objects != quotas.max_objects)
+    {
+        return false;
+    }
+    if let Some(website) = &update.website_access {
+        if bucket.website_access != website.enabled {
+            return false;
+        }
+        if website.enabled {
+            let Some(current) = &bucket.website_config else {
+                return false;
+            };
+            if Some(&current.index_document) != website.index_document.as_ref()
+                || current.error_document != website.error_document
+            {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+async fn prune_buckets(
+    garage: &Garage,
+    config: &Config,
+    garage_bucket_map: &HashMap<BucketAlias, String>,
+) -> Result<(), progenitor_client::Error> {
+    // Only buckets with a single alias, which is global, are candidates.
+    // Buckets reachable just by local aliases may belong to keys this tool
+    // does not manage, and a bucket with several aliases may still be in use
+    // under one it does not know about.
+    let configured = config
+        .buckets
+        .iter()
+        .filter_map(|bucket| garage_bucket_map.get(&BucketAlias::of(config, bucket)))
+        .collect::<HashSet<_>>();
+    let mut alias_counts = HashMap::<&String, usize>::new();
+    for bucket_id in garage_bucket_map.values() {
+        *alias_counts.entry(bucket_id).or_default() += 1;
+    }
+    let mut seen = HashSet::new();
+    let global = garage_bucket_map
+        .iter()
+        .filter(|(alias, _)| matches!(alias, BucketAlias::Global(_)));
+    for (alias, bucket_id) in global {
+        if configured.contains(bucket_id) || !seen.insert(bucket_id) {
+            continue;
+        }
+        if alias_counts[bucket_id] > 1 {
+            warn!(
+                "Not pruning bucket {:?} with id {:?}, it has more than one alias",
+                alias, bucket_id
+            );
+            continue;
+        }
+        if config.dry_run {
+            info!(
+                "Dry run: would delete bucket {:?} with id {:?}",
+                alias, bucket_id
+            );
+            continue;
+        }
+        info!("Deleting bucket {:?} with id {:?}...", alias, bucket_id);
+        garage.api.delete_bucket(bucket_id).await?;
+        info!("Bucket {:?} deleted", alias);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Credentials<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    admin_token: &'a str,
+    metrics_token: Option<&'a str>,
+}
+
+/// Writes the access key and tokens unredacted as JSON to `path`, or to
+/// stdout if `path` is `-`.
+pub fn write_credentials(config: &Config, path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(&Credentials {
+        access_key_id: &config.access_key_id,
+        secret_access_key: config.secret_access_key.expose(),
+        admin_token: config.admin_token.expose(),
+        metrics_token: config.metrics_token.as_ref().map(Secret::expose),
+    })?;
+    if path == Path::new("-") {
+        println!("{}", json);
+        return Ok(());
+    }
+    write_secret_file(path, &(json + "\n"))
+        .with_context(|| format!("Could not write credentials to {:?}", path))?;
+    info!("Credentials written to {:?}", path);
+    Ok(())
+}
+
+/// Launches a table and a block repair on this node and logs the state of
+/// its busy workers afterwards.
+pub async fn run_repair(garage: &Garage, config: &Config) -> Result<(), progenitor_client::Error> {
+    for repair_type in [RepairType::Tables, RepairType::Blocks] {
+        if config.dry_run {
+            info!("Dry run: would launch {:?} repair", repair_type);
+            continue;
+        }
+        info!("Launching {:?} repair...", repair_type);
+        let response = garage
+            .api
+            .launch_repair_operation(
+                "self",
+                &LocalLaunchRepairOperationRequest {
+                    repair_type: repair_type.clone(),
+                },
+            )
+            .await?
+            .into_inner();
+        for (node, error) in &response.error {
+            warn!(
+                "Could not launch {:?} repair on node {:?}: {}",
+                repair_type, node, error
+            );
+        }
+    }
+    if config.dry_run {
+        return Ok(());
+    }
+    let workers = garage
+        .api
+        .list_workers(
+            "self",
+            &LocalListWorkersRequest {
+                busy_only: Some(true),
+                error_only: None,
+            },
+        )
+        .await?
+        .into_inner();
+    for worker in workers.success.values().flatten() {
+        info!(
+            "Worker {:?} is {:?}{}",
+            worker.name,
+            worker.state,
+            worker
+                .progress
+                .as_ref()
+                .map(|progress| format!(", progress: {}", progress))
+                .unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Re-runs the access key and bucket steps of [`bootstrap`], e.g. to undo
+/// changes made by hand. The layout is left alone. Keys and buckets are read
+/// from the admin API on every call, each exactly once, so the comparison is
+/// never made against a stale snapshot.
+pub async fn reconcile(garage: &Garage, config: &Config) -> Result<()> {
+    info!("Reconciling access key and buckets...");
+    if config.manage_keys {
+        ensure_key(garage, config).await?;
+    }
+    if config.manage_buckets {
+        ensure_buckets(garage, config).await?;
+    }
+    Ok(())
+}
+
+/// Summary of what [`bootstrap`] found and changed.
+#[derive(Debug, Clone)]
+pub struct BootstrapReport {
+    pub node_id: String,
+    /// Layout version the node is part of, `None` if the layout is not
+    /// managed.
+    pub layout_version: Option<i64>,
+    pub keys_imported: u64,
+    pub buckets_created: u64,
+    pub buckets_found: u64,
+}
+
+pub async fn bootstrap(garage: &Garage, config: &Config) -> Result<BootstrapReport> {
+    let start = Instant::now();
+    let counts_before = garage.metrics.counts();
+    if config.dry_run {
+        warn!("Dry run enabled, no changes will be made");
+    }
+    let mut layout_version = None;
+    if config.manage_layout {
+        layout_version = Some(ensure_layout(garage, config).await?);
+        if config.repair_on_start {
+            run_repair(garage, config).await?;
+        }
+    } else {
+        info!("GARAGE_MANAGE_LAYOUT is disabled, skipping layout");
+    }
+    if config.manage_keys {
+        if config.reset_keys {
+            reset_keys(garage, config).await?;
+        }
+        ensure_key(garage, config).await?;
+    } else {
+        info!("GARAGE_MANAGE_KEYS is disabled, skipping access key");
+    }
+    if config.manage_buckets {
+        ensure_buckets(garage, config).await?;
+    } else {
+        info!("GARAGE_MANAGE_BUCKETS is disabled, skipping buckets");
+    }
+    garage.metrics.set_bootstrap(start.elapsed());
+    let counts = garage.metrics.counts();
+    let report = BootstrapReport {
+        node_id: garage.node_id.as_str().to_string(),
+        layout_version,
+        keys_imported: counts.keys_imported - counts_before.keys_imported,
+        buckets_created: counts.buckets_created - counts_before.buckets_created,
+        buckets_found: counts.buckets_found - counts_before.buckets_found,
+    };
+    info!(
+        "Bootstrap summary: node {:?}, layout version {}, {} access keys imported, {} buckets created, {} buckets found",
+        report.node_id,
+        report
+            .layout_version
+            .map_or_else(|| "unmanaged".to_string(), |version| version.to_string()),
+        report.keys_imported,
+        report.buckets_created,
+        report.buckets_found
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin_api::types::GetBucketInfoWebsiteResponse;
+
+    #[test]
+    fn garage_line_levels() {
+        assert_eq!(
+            line_level("2024-05-01T10:00:00.000Z  WARN garage_api: slow request"),
+            Some(Level::WARN)
+        );
+        assert_eq!(
+            line_level(
+                "\x1b[2m2024-05-01T10:00:00.000Z\x1b[0m \x1b[31mERROR\x1b[0m garage: failed"
+            ),
+            Some(Level::ERROR)
+        );
+        assert_eq!(line_level("2 nodes connected"), None);
+        assert_eq!(line_level("1 5 3"), None);
+        assert_eq!(line_level("Loading configuration"), None);
+    }
+
+    #[test]
+    fn key_permission_changes() {
+        let read_only = KeyPermissions {
+            read: true,
+            ..Default::default()
+        };
+        let read_write = KeyPermissions {
+            write: true,
+            ..read_only
+        };
+        assert_eq!(
+            read_write.without(read_only),
+            KeyPermissions {
+                write: true,
+                ..Default::default()
+            }
+        );
+        assert!(read_only.without(read_write).is_empty());
+        assert!(read_write.without(read_write).is_empty());
+        assert_eq!(
+            KeyPermissions::from(&ApiBucketKeyPerm {
+                owner: None,
+                read: Some(true),
+                write: Some(false),
+            }),
+            read_only
+        );
+    }
+
+    #[test]
+    fn bucket_up_to_date() {
+        let mut bucket: GetBucketInfoResponse = serde_json::from_value(serde_json::json!({
+            "id": "0123",
+            "created": "2024-05-01T10:00:00Z",
+            "globalAliases": ["site"],
+            "keys": [],
+            "bytes": 0,
+            "objects": 0,
+            "quotas": {},
+            "unfinishedMultipartUploadBytes": 0,
+            "unfinishedMultipartUploadParts": 0,
+            "unfinishedMultipartUploads": 0,
+            "unfinishedUploads": 0,
+            "websiteAccess": false,
+        }))
+        .unwrap();
+        let update = UpdateBucketRequestBody {
+            quotas: Some(ApiBucketQuotas {
+                max_size: Some(1024),
+                max_objects: None,
+            }),
+            website_access: Some(UpdateBucketWebsiteAccess {
+                enabled: true,
+                index_document: Some("index.html".into()),
+                error_document: None,
+            }),
+        };
+        assert!(!bucket_is_up_to_date(&bucket, &update));
+        bucket.quotas.max_size = Some(1024);
+        assert!(!bucket_is_up_to_date(&bucket, &update));
+        bucket.website_access = true;
+        assert!(!bucket_is_up_to_date(&bucket, &update));
+        bucket.website_config = Some(GetBucketInfoWebsiteResponse {
+            index_document: "index.html".into(),
+            error_document: Some("404.html".into()),
+        });
+        assert!(!bucket_is_up_to_date(&bucket, &update));
+        bucket.website_config.as_mut().unwrap().error_document = None;
+        assert!(bucket_is_up_to_date(&bucket, &update));
+        // Settings missing from the update are left as they are.
+        let update = UpdateBucketRequestBody {
+            quotas: None,
+            website_access: None,
+        };
+        bucket.quotas.max_objects = Some(10);
+        assert!(bucket_is_up_to_date(&bucket, &update));
+    }
+
+    #[test]
+    fn s3_endpoint_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("garage.toml");
+        std::fs::write(&path, include_str!("garage.toml")).unwrap();
+        assert_eq!(
+            s3_endpoint(&path).unwrap(),
+            ("http://127.0.0.1:3900".to_string(), "garage".to_string())
+        );
+        std::fs::write(
+            &path,
+            "[s3_api]\ns3_region = \"eu-west-1\"\napi_bind_addr = \"127.0.0.2:1111\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            s3_endpoint(&path).unwrap(),
+            ("http://127.0.0.2:1111".to_string(), "eu-west-1".to_string())
+        );
+    }
+}
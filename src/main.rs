@@ -1,310 +1,350 @@
-use std::collections::HashMap;
-use std::fs::write;
-use std::path::{Path, PathBuf};
-use std::process::{ExitStatus, exit};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::env;
+use std::fs::{read_to_string, remove_file, write};
+use std::future::pending;
+use std::io::{ErrorKind, stderr};
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::admin_api::Client;
-use crate::admin_api::types::{
-    AllowBucketKeyRequest, ApiBucketKeyPerm, ApplyClusterLayoutRequest, BucketKeyPermChangeRequest,
-    CreateBucketRequest, GetClusterStatusResponse, ImportKeyRequest, NodeRoleChange,
-    UpdateBucketRequestBody, UpdateBucketWebsiteAccess, UpdateClusterLayoutRequest,
-};
-use crate::config::{BucketPolicy, Config};
-use crate::random::random_hex;
 use anyhow::{Context, Result};
-use reqwest::header;
-use reqwest::header::HeaderMap;
-use thiserror::Error;
-use tokio::process::{Child, Command};
-use toml_edit::{DocumentMut, value};
-use tracing::{error, info, warn};
-
-pub mod admin_api;
-pub mod config;
-pub mod random;
+use clap::{Parser, Subcommand};
+use garage_bootstrap::config::DEFAULT_GARAGE_BINARY;
+use garage_bootstrap::health::{self, Health};
+use garage_bootstrap::metrics;
+use garage_bootstrap::notify::notify;
+use garage_bootstrap::{
+    Config, StartError, bootstrap, create_config, garage_version, reconcile, render_config,
+    run_garage_until, write_credentials,
+};
+use nix::sys::signal::Signal;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::sleep;
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-const GARAGE_CONFIG_PATH: &str = "/etc/garage.toml";
-const GARAGE_ADMIN_URL: &str = "http://127.0.0.1:3903";
-const GARAGE_START_TIMEOUT: Duration = Duration::from_secs(20);
-const GARAGE_START_POLL_INTERVAL: Duration = Duration::from_millis(100);
-const GARAGE_START_LOG_INTERVAL: Duration = Duration::from_secs(1);
+const GARAGE_RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
-pub struct Garage {
-    pub process: Child,
-    pub config_path: PathBuf,
-    pub api: Client,
-    pub node_id: NodeId,
+/// Loads the file named by `GARAGE_DOTENV`, or `./.env` if it exists, into
+/// the environment. Variables which are already set are not overridden.
+fn load_dotenv() -> Result<Option<PathBuf>> {
+    let path = match env::var_os("GARAGE_DOTENV") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let path = PathBuf::from(".env");
+            if !path.exists() {
+                return Ok(None);
+            }
+            path
+        }
+    };
+    dotenvy::from_path(&path).with_context(|| format!("Could not load {}", path.display()))?;
+    Ok(Some(path))
 }
 
-pub struct NodeId(String);
+/// Builds the log filter from `GARAGE_LOG_LEVEL`, falling back to `RUST_LOG`
+/// and finally to `info`.
+fn log_filter() -> Result<EnvFilter> {
+    match env::var("GARAGE_LOG_LEVEL") {
+        Ok(level) if !level.is_empty() => {
+            let level = level
+                .parse::<LevelFilter>()
+                .with_context(|| format!("Invalid GARAGE_LOG_LEVEL {:?}", level))?;
+            Ok(EnvFilter::default().add_directive(level.into()))
+        }
+        _ => Ok(EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy()),
+    }
+}
 
-#[derive(Debug, Error)]
-pub enum StartError {
-    #[error("failed to spawn garage process")]
-    Spawn(#[source] std::io::Error),
-    #[error("garage exited before becoming available with status {0}")]
-    Exited(ExitStatus),
-    #[error("timed out waiting for garage to become available after {timeout:?}")]
-    Timeout { timeout: Duration },
-    #[error("failed to check garage availability")]
-    AvailabilityCheck(#[source] std::io::Error),
-    #[error("invalid garage admin address {addr}")]
-    InvalidAdminAddr {
-        addr: String,
-        #[source]
-        source: std::net::AddrParseError,
-    },
-    #[error("unexpected number of nodes in status: {0}")]
-    UnexpectedNumberOfNodes(usize),
-    #[error("invalid garage cluster status {0:?}")]
-    InvalidClusterStatus(GetClusterStatusResponse),
+/// Doubles `base` with every restart, capped at `GARAGE_RESTART_BACKOFF_MAX`.
+fn restart_backoff(base: Duration, restarts: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(restarts.saturating_sub(1)))
+        .min(GARAGE_RESTART_BACKOFF_MAX)
 }
 
-pub fn delete_keys() -> Result<()> {
-    let db_path = Path::new("/var/lib/garage/meta/db.sqlite");
-    if db_path
-        .try_exists()
-        .context("Could not check existance of DB file")?
-    {
-        info!("Deleting all access keys...");
-        let conn = rusqlite::Connection::open("/var/lib/garage/meta/db.sqlite").unwrap();
-        let count = conn
-            .execute("DELETE FROM tree_key_COLON_table;", [])
-            .context("Could not delete keys in DB")?;
-        info!("All access keys removed: {}", count);
+/// Creates or removes `GARAGE_READY_FILE`. This is best effort, failures are
+/// only logged.
+fn set_ready_file(config: &Config, ready: bool) {
+    let Some(path) = &config.ready_file else {
+        return;
+    };
+    let result = if ready {
+        write(path, "")
     } else {
-        info!("db.sqlite does not exist. Skipping key deletion.")
+        match remove_file(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    };
+    if let Err(e) = result {
+        let action = if ready { "create" } else { "remove" };
+        warn!("Could not {} ready file {:?}: {}", action, path, e);
     }
-    Ok(())
 }
 
-pub fn create_config(config: &Config) -> Result<()> {
-    let mut doc = include_str!("garage.toml")
-        .parse::<DocumentMut>()
-        .expect("Bundled garage.toml is invalid");
-    doc["rpc_secret"] = value(random_hex(32));
-    doc["admin"]["admin_token"] = value(config.admin_token.clone());
-    doc["admin"]["metrics_token"] = value(config.metrics_token.clone());
-    write(GARAGE_CONFIG_PATH, doc.to_string())?;
-    Ok(())
+/// Completes after `interval`, never if periodic reconciliation is disabled.
+async fn reconcile_timer(interval: Option<Duration>) {
+    match interval {
+        Some(interval) => sleep(interval).await,
+        None => pending().await,
+    }
+}
+
+struct ShutdownSignals {
+    sigterm: tokio::signal::unix::Signal,
+    sigint: tokio::signal::unix::Signal,
+}
+
+impl ShutdownSignals {
+    /// Installs the handlers. Signals received from then on are buffered
+    /// until [`ShutdownSignals::recv`] is called instead of killing the
+    /// wrapper.
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.sigterm.recv() => Signal::SIGTERM,
+            _ = self.sigint.recv() => Signal::SIGINT,
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Log the changes bootstrapping would make without applying them
+    #[arg(long, global = true)]
+    dry_run: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start garage and bootstrap it (default)
+    Run,
+    /// Load and validate the configuration without starting garage
+    ValidateConfig,
+    /// Write the generated garage config to stdout
+    PrintConfig,
+    /// Print the versions of garage-bootstrap and the garage binary
+    Version,
+}
+
+async fn print_version() {
+    println!("garage-bootstrap {}", env!("CARGO_PKG_VERSION"));
+    let binary = env::var_os("GARAGE_BINARY")
+        .filter(|binary| !binary.is_empty())
+        .map_or_else(|| PathBuf::from(DEFAULT_GARAGE_BINARY), PathBuf::from);
+    match garage_version(&binary).await {
+        Ok(version) => println!("{}", version),
+        Err(e) => println!("garage: unavailable ({:#})", e),
+    }
 }
 
-async fn wait_for_garage(child: &mut Child, admin_api: &Client) -> Result<NodeId, StartError> {
-    let start = Instant::now();
-    let mut next_log = GARAGE_START_LOG_INTERVAL;
-    loop {
-        if let Some(status) = child.try_wait().map_err(StartError::AvailabilityCheck)? {
-            error!("Garage exited after {:.1}s", start.elapsed().as_secs_f64());
-            return Err(StartError::Exited(status));
+async fn run(config: Config) -> Result<()> {
+    // Installed first, so garage is also stopped cleanly if a signal arrives
+    // while it is still starting.
+    let mut shutdown = ShutdownSignals::new().context("Could not install signal handlers")?;
+    let health = Arc::new(Health::default());
+    if let Some(addr) = config.health_addr {
+        health::spawn_server(addr, health.clone())
+            .await
+            .context("Could not start health endpoint")?;
+    }
+    if let Some(addr) = config.metrics_proxy_addr {
+        metrics::spawn_proxy(addr, &config)
+            .await
+            .context("Could not start metrics proxy")?;
+    }
+    // A ready file left behind by a previous run must not signal readiness.
+    set_ready_file(&config, false);
+    let mut restarts = 0;
+    let mut shutdown_requested = false;
+    let exit_status = 'run: loop {
+        create_config(&config)?;
+        let stopping = async {
+            let signal = shutdown.recv().await;
+            notify("STOPPING=1");
+            signal
+        };
+        let mut garage = match run_garage_until(&config, stopping).await {
+            Ok(garage) => garage,
+            Err(StartError::Interrupted { signal, status }) => {
+                info!("Received {} while garage was starting", signal);
+                shutdown_requested = true;
+                break 'run status;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        health.set_api(garage.api.clone());
+        health.set_metrics(garage.metrics.clone());
+        tokio::select! {
+            report = bootstrap(&garage, &config) => {
+                report?;
+            }
+            signal = shutdown.recv() => {
+                info!("Received {} while bootstrapping", signal);
+                shutdown_requested = true;
+                notify("STOPPING=1");
+                break 'run garage.stop(signal, config.shutdown_grace).await?;
+            }
         }
-        match admin_api.get_cluster_status().await {
-            Ok(status) => {
-                if status.nodes.len() != 1 {
-                    return Err(StartError::UnexpectedNumberOfNodes(status.nodes.len()));
+        if config.dry_run {
+            info!("Dry run complete, stopping garage...");
+            if let Err(e) = remove_file(&garage.config_path) {
+                warn!("Could not remove {:?}: {}", garage.config_path, e);
+            }
+            notify("STOPPING=1");
+            garage.shutdown(config.shutdown_grace).await?;
+            return Ok(());
+        }
+        if let Some(path) = &config.credentials_out {
+            write_credentials(&config, path)?;
+        }
+        info!("Bootstrapping complete.");
+        health.set_bootstrapped(true);
+        set_ready_file(&config, true);
+        notify("READY=1\nSTATUS=Bootstrapping complete");
+        let exit_status = loop {
+            tokio::select! {
+                status = garage.wait() => break status?,
+                signal = shutdown.recv() => {
+                    shutdown_requested = true;
+                    notify("STOPPING=1");
+                    break 'run garage.stop(signal, config.shutdown_grace).await?;
                 }
-                if !status.nodes[0].is_up {
-                    if start.elapsed() > next_log {
-                        next_log += GARAGE_START_LOG_INTERVAL;
-                        info!(
-                            "Waiting for garage... ({:.1}s)",
-                            start.elapsed().as_secs_f64()
-                        );
+                _ = reconcile_timer(config.reconcile_interval) => {
+                    // If garage exits meanwhile the admin calls fail and the
+                    // exit is picked up by the next iteration.
+                    tokio::select! {
+                        result = reconcile(&garage, &config) => {
+                            if let Err(e) = result {
+                                warn!("Reconciliation failed: {:#}", e);
+                            }
+                        }
+                        signal = shutdown.recv() => {
+                            info!("Received {} while reconciling", signal);
+                            shutdown_requested = true;
+                            notify("STOPPING=1");
+                            break 'run garage.stop(signal, config.shutdown_grace).await?;
+                        }
                     }
-                } else {
-                    info!("Garage ready after {:.1}s", start.elapsed().as_secs_f64());
-                    return Ok(NodeId(status.nodes[0].id.clone()));
-                }
-            }
-            Err(_) => {
-                if start.elapsed() > next_log {
-                    next_log += GARAGE_START_LOG_INTERVAL;
-                    info!(
-                        "Waiting for garage... ({:.1}s)",
-                        start.elapsed().as_secs_f64()
-                    );
                 }
             }
         };
-        if start.elapsed() >= GARAGE_START_TIMEOUT {
+        health.set_bootstrapped(false);
+        set_ready_file(&config, false);
+        if exit_status.success() || !config.restart_on_failure {
+            break exit_status;
+        }
+        if restarts >= config.max_restarts {
             error!(
-                "Garage not ready after {:.1}s",
-                start.elapsed().as_secs_f64()
+                "Garage exited with {}, giving up after {} restarts",
+                exit_status, restarts
             );
-            return Err(StartError::Timeout {
-                timeout: GARAGE_START_TIMEOUT,
-            });
+            break exit_status;
         }
-        thread::sleep(GARAGE_START_POLL_INTERVAL);
-    }
-}
-
-pub async fn run_garage(config: &Config) -> Result<Garage, StartError> {
-    info!("Starting garage...");
-    let config_path = PathBuf::from(GARAGE_CONFIG_PATH);
-    let mut child = Command::new("/garage")
-        .arg("-c")
-        .arg(&config_path)
-        .arg("server")
-        .spawn()
-        .map_err(StartError::Spawn)?;
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        header::AUTHORIZATION,
-        format!("Bearer {}", config.admin_token).parse().unwrap(),
-    );
-    let client = admin_api::Client::new_with_client(
-        GARAGE_ADMIN_URL,
-        reqwest::Client::builder()
-            .connect_timeout(Duration::from_secs(1))
-            .timeout(Duration::from_secs(1))
-            .default_headers(headers)
-            .build()
-            .unwrap(),
-    );
-    let node_id = wait_for_garage(&mut child, &client).await?;
-    Ok(Garage {
-        process: child,
-        config_path,
-        api: client,
-        node_id,
-    })
-}
-
-async fn ensure_layout(garage: &Garage) -> Result<(), progenitor_client::Error> {
-    let layout = garage.api.get_cluster_layout().await?;
-    if layout.version > 0 {
-        info!("Layout version > 0, skipping initialization");
+        restarts += 1;
+        let backoff = restart_backoff(config.restart_backoff, restarts);
+        warn!(
+            "Garage exited with {}, restarting in {:?} ({}/{})...",
+            exit_status, backoff, restarts, config.max_restarts
+        );
+        notify(&format!(
+            "STATUS=Restarting garage ({}/{})",
+            restarts, config.max_restarts
+        ));
+        tokio::select! {
+            _ = sleep(backoff) => (),
+            _ = shutdown.recv() => break exit_status,
+        }
+    };
+    set_ready_file(&config, false);
+    // Garage terminated by the signal we forwarded, or killed after the grace
+    // period, stopped as requested.
+    if shutdown_requested && exit_status.signal().is_some() {
+        info!("Garage stopped by {}", exit_status);
         return Ok(());
     }
-    info!("No layout found. Updating cluster...");
-    let layout = garage
-        .api
-        .update_cluster_layout(&UpdateClusterLayoutRequest {
-            parameters: None,
-            roles: vec![NodeRoleChange::Variant1 {
-                capacity: Some(i64::MAX),
-                tags: vec![],
-                zone: "dc1".into(),
-                id: garage.node_id.0.clone(),
-            }],
-        })
-        .await?;
-    info!("Layout updated. Applying layout...");
-    garage
-        .api
-        .apply_cluster_layout(&ApplyClusterLayoutRequest {
-            version: layout.version + 1,
-        })
-        .await?;
-    info!("Layout applied.");
+    if !exit_status.success() {
+        exit(exit_status.code().unwrap_or(1));
+    }
     Ok(())
 }
 
-async fn ensure_key(garage: &Garage, config: &Config) -> Result<(), progenitor_client::Error> {
-    garage
-        .api
-        .import_key(&ImportKeyRequest {
-            name: None,
-            access_key_id: config.access_key_id.clone(),
-            secret_access_key: config.secret_access_key.clone(),
-        })
-        .await?;
-    Ok(())
+fn validate_config(config: &Config) {
+    println!("Configuration is valid:");
+    println!("{}", config.redacted());
+    println!();
+    println!("Garage config would be written to {:?}", config.config_path);
+    if config.access_key_generated {
+        println!(
+            "Access key {:?} would be generated and imported, it changes on every start",
+            config.access_key_id
+        );
+    } else {
+        println!(
+            "Access key {:?} would be imported unless it already exists",
+            config.access_key_id
+        );
+    }
+    for bucket in &config.buckets {
+        println!(
+            "Bucket {:?} would be created or updated with policy {:?}",
+            bucket.name, bucket.policy
+        );
+    }
 }
 
-async fn ensure_buckets(garage: &Garage, config: &Config) -> Result<(), progenitor_client::Error> {
-    let mut garage_bucket_map = HashMap::<String, String>::new();
-    for bucket in &garage.api.list_buckets().await?.0 {
-        if bucket.global_aliases.is_empty() {
-            warn!("Ignoring bucket without a global alias: {:?}", bucket);
-            continue;
-        }
-        if bucket.global_aliases.len() > 1 {
-            warn!(
-                "Ignoring bucket with more than one global alias: {:?}",
-                bucket
-            );
-            continue;
-        }
-        garage_bucket_map.insert(bucket.global_aliases[0].clone(), bucket.id.clone());
-    }
-    for bucket_config in &config.buckets {
-        let bucket_id = match garage_bucket_map.get(&bucket_config.name) {
-            None => {
-                info!("Creating bucket {:?}...", bucket_config.name);
-                let bucket = garage
-                    .api
-                    .create_bucket(&CreateBucketRequest {
-                        global_alias: Some(bucket_config.name.clone()),
-                        local_alias: None,
-                    })
-                    .await?;
-                info!("Bucket {:?} created", bucket_config.name);
-                bucket.id.clone()
-            }
-            Some(bucket_id) => {
-                info!(
-                    "Bucket {:?} found with id {:?}",
-                    bucket_config.name, bucket_id
-                );
-                bucket_id.clone()
-            }
-        };
-        info!("Updating bucket {:?}", bucket_config.name);
-        garage
-            .api
-            .update_bucket(
-                &bucket_id,
-                &UpdateBucketRequestBody {
-                    quotas: None,
-                    website_access: Some(match bucket_config.policy {
-                        BucketPolicy::Private => UpdateBucketWebsiteAccess {
-                            enabled: false,
-                            error_document: None,
-                            index_document: None,
-                        },
-                        BucketPolicy::Public => UpdateBucketWebsiteAccess {
-                            enabled: true,
-                            error_document: None,
-                            index_document: Some("index.html".into()),
-                        },
-                    }),
-                },
-            )
-            .await?;
-        info!("Granting access to bucket {:?}", bucket_config.name);
-        garage
-            .api
-            .allow_bucket_key(&AllowBucketKeyRequest(BucketKeyPermChangeRequest {
-                access_key_id: config.access_key_id.clone(),
-                bucket_id: bucket_id,
-                permissions: ApiBucketKeyPerm {
-                    owner: Some(true),
-                    read: Some(true),
-                    write: Some(true),
-                },
-            }))
-            .await?;
+fn print_config(config: &Config) -> Result<()> {
+    match render_config(config, false)? {
+        Some(doc) => print!("{}", doc),
+        None => print!(
+            "{}",
+            read_to_string(&config.config_path)
+                .with_context(|| format!("Could not read {:?}", config.config_path))?
+        ),
     }
     Ok(())
 }
 
 #[tokio::main]
 pub async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let config = Config::from_env().context("Could not load config")?;
-    delete_keys()?;
-    create_config(&config)?;
-    let mut garage = run_garage(&config).await?;
-    ensure_layout(&garage).await?;
-    ensure_key(&garage, &config).await?;
-    ensure_buckets(&garage, &config).await?;
-    info!("Bootstrapping complete.");
-    let exit_status = garage.process.wait().await?;
-    if !exit_status.success() {
-        exit(exit_status.code().unwrap_or(1));
+    // Loaded before the log filter is built so it can set `GARAGE_LOG_LEVEL`.
+    let dotenv = load_dotenv()?;
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter()?)
+        .with_writer(stderr)
+        .init();
+    if let Some(path) = dotenv {
+        info!("Loaded environment from {}", path.display());
+    }
+    let cli = Cli::parse();
+    // Does not need a valid configuration.
+    if matches!(cli.command, Some(Command::Version)) {
+        print_version().await;
+        return Ok(());
+    }
+    let mut config = Config::from_env().context("Could not load config")?;
+    config.dry_run |= cli.dry_run;
+    debug!("Loaded config: {}", config.redacted());
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config).await,
+        Command::ValidateConfig => {
+            validate_config(&config);
+            Ok(())
+        }
+        Command::PrintConfig => print_config(&config),
+        Command::Version => unreachable!("handled before loading the config"),
     }
-    Ok(())
 }
@@ -0,0 +1,236 @@
+//! Metrics about the bootstrap itself and a proxy for garage's `/metrics`
+//! endpoint which adds the metrics token, so scrapers only need network
+//! access to the proxy.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use reqwest::header;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::config::{BucketConfig, Config, Secret};
+use crate::health::{method_not_allowed, not_found, read_request, response};
+
+/// Counters and timings of one garage run, rendered in the Prometheus text
+/// format by the health endpoint.
+#[derive(Default)]
+pub struct BootstrapMetrics {
+    startup_millis: AtomicU64,
+    bootstrap_millis: AtomicU64,
+    layout_initialized: AtomicBool,
+    keys_imported: AtomicU64,
+    buckets_created: AtomicU64,
+    buckets_found: AtomicU64,
+    bucket_tags: Vec<(String, BTreeMap<String, String>)>,
+}
+
+/// Snapshot of the counters of [`BootstrapMetrics`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Counts {
+    pub keys_imported: u64,
+    pub buckets_created: u64,
+    pub buckets_found: u64,
+}
+
+impl BootstrapMetrics {
+    /// Creates empty metrics reporting the tags of `buckets`.
+    pub fn new(buckets: &[BucketConfig]) -> Self {
+        Self {
+            bucket_tags: buckets
+                .iter()
+                .filter(|bucket| !bucket.tags.is_empty())
+                .map(|bucket| (bucket.name.clone(), bucket.tags.clone()))
+                .collect(),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn set_startup(&self, duration: Duration) {
+        self.startup_millis
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_bootstrap(&self, duration: Duration) {
+        self.bootstrap_millis
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn layout_initialized(&self) {
+        self.layout_initialized.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn key_imported(&self) {
+        self.keys_imported.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bucket_created(&self) {
+        self.buckets_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bucket_found(&self) {
+        self.buckets_found.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn counts(&self) -> Counts {
+        Counts {
+            keys_imported: self.keys_imported.load(Ordering::Relaxed),
+            buckets_created: self.buckets_created.load(Ordering::Relaxed),
+            buckets_found: self.buckets_found.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let seconds = |millis: &AtomicU64| millis.load(Ordering::Relaxed) as f64 / 1000.0;
+        let mut output = String::new();
+        for (name, kind, help, value) in [
+            (
+                "garage_bootstrap_startup_seconds",
+                "gauge",
+                "Time until garage became available",
+                seconds(&self.startup_millis),
+            ),
+            (
+                "garage_bootstrap_duration_seconds",
+                "gauge",
+                "Time the last bootstrap took",
+                seconds(&self.bootstrap_millis),
+            ),
+            (
+                "garage_bootstrap_layout_initialized",
+                "gauge",
+                "Whether the cluster layout was initialized by this run",
+                f64::from(u8::from(self.layout_initialized.load(Ordering::Relaxed))),
+            ),
+            (
+                "garage_bootstrap_keys_imported_total",
+                "counter",
+                "Access keys imported",
+                self.keys_imported.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "garage_bootstrap_buckets_created_total",
+                "counter",
+                "Buckets created",
+                self.buckets_created.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "garage_bootstrap_buckets_found_total",
+                "counter",
+                "Configured buckets which already existed",
+                self.buckets_found.load(Ordering::Relaxed) as f64,
+            ),
+        ] {
+            let _ = write!(
+                output,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        if !self.bucket_tags.is_empty() {
+            output.push_str(
+                "# HELP garage_bootstrap_bucket_tag Tags of configured buckets, always 1\n\
+                 # TYPE garage_bootstrap_bucket_tag gauge\n",
+            );
+            for (bucket, tags) in &self.bucket_tags {
+                for (tag, value) in tags {
+                    let _ = writeln!(
+                        output,
+                        r#"garage_bootstrap_bucket_tag{{bucket="{}",tag="{}",value="{}"}} 1"#,
+                        escape_label(bucket),
+                        escape_label(tag),
+                        escape_label(value)
+                    );
+                }
+            }
+        }
+        output
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+struct Upstream {
+    http: reqwest::Client,
+    url: String,
+    token: Option<Secret>,
+}
+
+/// Binds the metrics proxy and serves it in a background task.
+pub async fn spawn_proxy(addr: SocketAddr, config: &Config) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics proxy listening on {}", addr);
+    let upstream = Arc::new(Upstream {
+        http: crate::with_admin_ca_certs(config, reqwest::Client::builder())
+            .connect_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap(),
+        url: format!("{}/metrics", crate::admin_url(config)),
+        token: config.metrics_token.clone(),
+    });
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let upstream = upstream.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream, &upstream).await {
+                            debug!("Metrics request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Could not accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle(stream: TcpStream, upstream: &Upstream) -> std::io::Result<()> {
+    let (mut stream, request) = read_request(stream).await?;
+    let request = request
+        .as_ref()
+        .map(|(method, path)| (method.as_str(), path.as_str()));
+    let response = match request {
+        Some(("GET", "/metrics")) => match fetch(upstream).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Could not fetch garage metrics: {}", e);
+                response("502 Bad Gateway", "text/plain", "bad gateway\n")
+            }
+        },
+        Some(("GET", _)) => not_found(),
+        _ => method_not_allowed(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Fetches the metrics from garage and relays its status, content type and
+/// body.
+async fn fetch(upstream: &Upstream) -> reqwest::Result<String> {
+    let mut request = upstream.http.get(&upstream.url);
+    if let Some(token) = &upstream.token {
+        request = request.bearer_auth(token.expose());
+    }
+    let upstream_response = request.send().await?;
+    let status = upstream_response.status();
+    let content_type = upstream_response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("text/plain")
+        .to_string();
+    let body = upstream_response.text().await?;
+    Ok(response(&status.to_string(), &content_type, &body))
+}
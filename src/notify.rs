@@ -0,0 +1,45 @@
+use std::env;
+use std::ffi::OsStr;
+use std::os::unix::net::UnixDatagram;
+
+use tracing::warn;
+
+/// Sends a state update to the service manager if `NOTIFY_SOCKET` is set,
+/// e.g. `READY=1` or `STATUS=...`. See `sd_notify(3)`.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("Could not notify service manager via {:?}: {}", socket, e);
+    }
+}
+
+fn send(socket: &OsStr, state: &str) -> std::io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    let bytes = socket.as_encoded_bytes();
+    if let Some(name) = bytes.strip_prefix(b"@") {
+        send_abstract(&datagram, name, state)?;
+    } else {
+        datagram.send_to(state.as_bytes(), socket)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(datagram: &UnixDatagram, name: &[u8], state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    datagram.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_datagram: &UnixDatagram, _name: &[u8], _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
@@ -1,17 +1,17 @@
-use base64::{Engine, prelude::BASE64_STANDARD};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
 
-fn random_bytes(n: usize) -> Vec<u8> {
+fn random_bytes(n: usize) -> Result<Vec<u8>, getrandom::Error> {
     let mut bytes = vec![0u8; n];
-    getrandom::fill(&mut bytes).unwrap();
-    bytes
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes)
 }
 
-pub fn random_hex(n: usize) -> String {
-    hex::encode(random_bytes(n))
+pub fn random_hex(n: usize) -> Result<String, getrandom::Error> {
+    Ok(hex::encode(random_bytes(n)?))
 }
 
-pub fn random_base64(n: usize) -> String {
-    let mut bytes = vec![0u8; n];
-    getrandom::fill(&mut bytes).unwrap();
-    BASE64_STANDARD.encode(bytes)
+/// URL-safe base64 without padding, so the value can be used in headers,
+/// URLs and env files without quoting.
+pub fn random_base64(n: usize) -> Result<String, getrandom::Error> {
+    Ok(BASE64_URL_SAFE_NO_PAD.encode(random_bytes(n)?))
 }
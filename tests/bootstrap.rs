@@ -0,0 +1,221 @@
+//! End-to-end test bootstrapping a real garage. It is skipped unless
+//! `GARAGE_TEST_BINARY` points to a garage binary, e.g.
+//! `GARAGE_TEST_BINARY=/usr/local/bin/garage cargo test --test bootstrap`.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::write;
+use std::net::{SocketAddr, TcpListener};
+
+use garage_bootstrap::admin_api::types::{
+    AddBucketAliasRequest, CreateBucketLocalAlias, UpdateKeyRequestBody,
+};
+use garage_bootstrap::{
+    Config, CreateBucketRequest, CreateKeyRequest, bootstrap, create_config, run_garage,
+};
+
+const ACCESS_KEY_ID: &str = "GK0123456789abcdef01234567";
+const SECRET_ACCESS_KEY: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+/// Returns a port which was free a moment ago, so parallel runs don't clash.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn bootstrap_real_garage() {
+    let Ok(binary) = env::var("GARAGE_TEST_BINARY") else {
+        eprintln!("GARAGE_TEST_BINARY is not set, skipping");
+        return;
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    let rpc_addr = free_addr();
+    let s3_addr = free_addr();
+    write(
+        path("extra.toml"),
+        format!(
+            "rpc_bind_addr = \"{}\"\nrpc_public_addr = \"{}\"\n\n[k2v_api]\napi_bind_addr = \"{}\"\n",
+            rpc_addr,
+            rpc_addr,
+            free_addr()
+        ),
+    )
+    .unwrap();
+    let mut vars: HashMap<_, _> = [
+        ("GARAGE_BINARY", binary),
+        ("GARAGE_CONFIG_PATH", path("garage.toml")),
+        ("GARAGE_CONFIG_EXTRA_PATH", path("extra.toml")),
+        ("GARAGE_METADATA_DIR", path("meta")),
+        ("GARAGE_DATA_DIR", path("data")),
+        ("GARAGE_S3_API_BIND", s3_addr.to_string()),
+        ("GARAGE_WEB_BIND", free_addr().to_string()),
+        ("GARAGE_ADMIN_BIND", free_addr().to_string()),
+        ("GARAGE_ACCESS_KEY_ID", ACCESS_KEY_ID.into()),
+        ("GARAGE_SECRET_ACCESS_KEY", SECRET_ACCESS_KEY.into()),
+        (
+            "GARAGE_BUCKETS_JSON",
+            r#"[
+                {
+                    "name": "uploads",
+                    "expire_after_days": 7,
+                    "cors": [{ "allowed_origins": ["https://app.example.com"], "allowed_methods": ["PUT"] }]
+                },
+                { "name": "site", "policy": "public" }
+            ]"#
+            .into(),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect();
+    let config = Config::from_map(&vars).unwrap();
+
+    create_config(&config).unwrap();
+    let garage = run_garage(&config).await.unwrap();
+    let report = bootstrap(&garage, &config).await.unwrap();
+    assert_eq!(report.node_id, garage.node_id.as_str());
+    assert_eq!(report.keys_imported, 1);
+    assert_eq!(report.buckets_created, 2);
+    // A second run must find everything in place.
+    let report = bootstrap(&garage, &config).await.unwrap();
+    assert_eq!(report.keys_imported, 0);
+    assert_eq!(report.buckets_created, 0);
+    assert_eq!(report.buckets_found, 2);
+
+    let layout = garage.admin().get_cluster_layout().await.unwrap();
+    let role = layout
+        .roles
+        .iter()
+        .find(|role| role.id == garage.node_id.as_str())
+        .expect("node has no role in the layout");
+    assert_eq!(role.zone, config.zone);
+    assert_eq!(report.layout_version, Some(layout.version));
+
+    // CORS rules and lifecycle go through the S3 API and its signatures,
+    // the preflight shows the rules arrived.
+    let preflight = reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("http://{}/uploads/file.txt", s3_addr),
+        )
+        .header("Origin", "https://app.example.com")
+        .header("Access-Control-Request-Method", "PUT")
+        .send()
+        .await
+        .unwrap();
+    assert!(preflight.status().is_success(), "{}", preflight.status());
+    assert_eq!(
+        preflight.headers()["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+
+    let key = garage
+        .admin()
+        .get_key_info(Some(ACCESS_KEY_ID), None, Some(true))
+        .await
+        .unwrap();
+    assert_eq!(key.secret_access_key.as_deref(), Some(SECRET_ACCESS_KEY));
+
+    for (alias, website_access) in [("uploads", false), ("site", true)] {
+        let bucket = garage
+            .admin()
+            .get_bucket_info(Some(alias), None, None)
+            .await
+            .unwrap();
+        assert_eq!(bucket.website_access, website_access, "bucket {}", alias);
+        let grant = bucket
+            .keys
+            .iter()
+            .find(|key| key.access_key_id == ACCESS_KEY_ID)
+            .unwrap_or_else(|| panic!("access key has no permissions on bucket {}", alias));
+        assert_eq!(grant.permissions.owner, Some(true));
+        assert_eq!(grant.permissions.read, Some(true));
+        assert_eq!(grant.permissions.write, Some(true));
+    }
+
+    // Pruning deletes unlisted buckets with a global alias, but not those
+    // only reachable by a local alias of a key managed elsewhere.
+    let admin = garage.admin();
+    admin
+        .create_bucket(&CreateBucketRequest {
+            global_alias: Some("stray".into()),
+            local_alias: None,
+        })
+        .await
+        .unwrap();
+    let other_key = admin
+        .create_key(&CreateKeyRequest(UpdateKeyRequestBody {
+            name: Some("other".into()),
+            ..Default::default()
+        }))
+        .await
+        .unwrap();
+    admin
+        .create_bucket(&CreateBucketRequest {
+            global_alias: None,
+            local_alias: Some(CreateBucketLocalAlias {
+                access_key_id: other_key.access_key_id.clone(),
+                alias: "private-stuff".into(),
+                allow: None,
+            }),
+        })
+        .await
+        .unwrap();
+    let aliased = admin
+        .create_bucket(&CreateBucketRequest {
+            global_alias: Some("stray-aliased".into()),
+            local_alias: None,
+        })
+        .await
+        .unwrap();
+    admin
+        .add_bucket_alias(&AddBucketAliasRequest::Variant0 {
+            bucket_id: aliased.id.clone(),
+            global_alias: "stray-aliased-too".into(),
+        })
+        .await
+        .unwrap();
+    vars.insert("GARAGE_PRUNE_BUCKETS".into(), "true".into());
+    let prune_config = Config::from_map(&vars).unwrap();
+    bootstrap(&garage, &prune_config).await.unwrap();
+    let buckets = admin.list_buckets().await.unwrap().into_inner().0;
+    assert!(
+        !buckets
+            .iter()
+            .any(|bucket| bucket.global_aliases.contains(&"stray".to_string()))
+    );
+    assert!(buckets.iter().any(|bucket| {
+        bucket
+            .local_aliases
+            .iter()
+            .any(|alias| alias.alias == "private-stuff")
+    }));
+    assert!(buckets.iter().any(|bucket| bucket.id == aliased.id));
+    assert_eq!(buckets.len(), 4);
+
+    // Resetting keys removes every other key but keeps the configured one,
+    // also on the following restarts.
+    vars.insert("GARAGE_RESET_KEYS".into(), "true".into());
+    let reset_config = Config::from_map(&vars).unwrap();
+    for _ in 0..2 {
+        let report = bootstrap(&garage, &reset_config).await.unwrap();
+        assert_eq!(report.keys_imported, 0);
+    }
+    let keys = admin.list_keys().await.unwrap().into_inner().0;
+    assert_eq!(
+        keys.iter().map(|key| key.id.as_str()).collect::<Vec<_>>(),
+        [ACCESS_KEY_ID]
+    );
+    let key = admin
+        .get_key_info(Some(ACCESS_KEY_ID), None, Some(true))
+        .await
+        .unwrap();
+    assert_eq!(key.secret_access_key.as_deref(), Some(SECRET_ACCESS_KEY));
+
+    let status = garage.shutdown(config.shutdown_grace).await.unwrap();
+    assert!(status.code().is_none_or(|code| code == 0), "{}", status);
+}
@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::fs::metadata;
+use std::os::unix::fs::PermissionsExt;
+
+use garage_bootstrap::config::BucketMode;
+use garage_bootstrap::{
+    BucketPolicy, Config, ConfigError, create_config, garage_config_path, render_config,
+    write_credentials,
+};
+
+const ACCESS_KEY_ID: &str = "GK0123456789abcdef01234567";
+const SECRET_ACCESS_KEY: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+/// A valid set of variables with `overrides` applied, an empty value removes
+/// the variable.
+fn vars(overrides: &[(&str, &str)]) -> HashMap<String, String> {
+    let mut vars: HashMap<_, _> = [
+        ("GARAGE_ADMIN_TOKEN", "admin-token"),
+        ("GARAGE_METRICS_TOKEN", "metrics-token"),
+        ("GARAGE_ACCESS_KEY_ID", ACCESS_KEY_ID),
+        ("GARAGE_SECRET_ACCESS_KEY", SECRET_ACCESS_KEY),
+        ("GARAGE_BUCKETS", "uploads"),
+    ]
+    .into_iter()
+    .chain(overrides.iter().copied())
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect();
+    vars.retain(|_, value| !value.is_empty());
+    vars
+}
+
+fn errors(overrides: &[(&str, &str)]) -> Vec<ConfigError> {
+    match Config::from_map(&vars(overrides)) {
+        Ok(_) => Vec::new(),
+        Err(ConfigError::Multiple(errors)) => errors,
+        Err(error) => vec![error],
+    }
+}
+
+#[test]
+fn valid_config() {
+    let config = Config::from_map(&vars(&[])).unwrap();
+    assert_eq!(config.access_key_id, ACCESS_KEY_ID);
+    assert_eq!(config.admin_token.expose(), "admin-token");
+    assert_eq!(config.buckets.len(), 1);
+    assert_eq!(config.buckets[0].name, "uploads");
+}
+
+#[test]
+fn no_buckets() {
+    let config = Config::from_map(&vars(&[("GARAGE_BUCKETS", "")])).unwrap();
+    assert!(config.buckets.is_empty());
+    let config = Config::from_map(&vars(&[("GARAGE_BUCKETS", "  ")])).unwrap();
+    assert!(config.buckets.is_empty());
+    assert!(matches!(
+        errors(&[("GARAGE_BUCKETS", "uploads,")])[..],
+        [ConfigError::InvalidBucketEntry { .. }]
+    ));
+    assert!(matches!(
+        errors(&[("GARAGE_BUCKETS", ""), ("GARAGE_PRUNE_BUCKETS", "true")])[..],
+        [ConfigError::PruneWithoutBuckets]
+    ));
+}
+
+#[test]
+fn generated_access_key() {
+    let config = Config::from_map(&vars(&[
+        ("GARAGE_ACCESS_KEY_ID", ""),
+        ("GARAGE_SECRET_ACCESS_KEY", ""),
+    ]))
+    .unwrap();
+    assert!(config.access_key_generated);
+    assert!(config.access_key_id.starts_with("GK"));
+    assert!(!Config::from_map(&vars(&[])).unwrap().access_key_generated);
+}
+
+#[test]
+fn incomplete_access_key() {
+    assert!(matches!(
+        errors(&[("GARAGE_SECRET_ACCESS_KEY", "")])[..],
+        [ConfigError::IncompleteAccessKey {
+            set: "GARAGE_ACCESS_KEY_ID",
+            missing: "GARAGE_SECRET_ACCESS_KEY",
+        }]
+    ));
+}
+
+#[test]
+fn bucket_policies() {
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS",
+        "uploads,static:public,media:PRIVATE",
+    )]))
+    .unwrap();
+    let policies: Vec<_> = config
+        .buckets
+        .iter()
+        .map(|bucket| (bucket.name.as_str(), bucket.policy))
+        .collect();
+    assert!(matches!(
+        policies[..],
+        [
+            ("uploads", BucketPolicy::Private),
+            ("static", BucketPolicy::Public),
+            ("media", BucketPolicy::Private),
+        ]
+    ));
+}
+
+#[test]
+fn bucket_policy_whitespace_and_case() {
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS",
+        "photos: public,media:\tPrivate ",
+    )]))
+    .unwrap();
+    assert!(matches!(config.buckets[0].policy, BucketPolicy::Public));
+    assert!(matches!(config.buckets[1].policy, BucketPolicy::Private));
+}
+
+#[test]
+fn empty_bucket_policy() {
+    let errors = errors(&[("GARAGE_BUCKETS", "photos: ")]);
+    assert_eq!(
+        errors.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        ["invalid bucket policy \"\" for bucket photos"]
+    );
+}
+
+#[test]
+fn invalid_bucket_policy() {
+    assert!(matches!(
+        &errors(&[("GARAGE_BUCKETS", "uploads:open")])[..],
+        [ConfigError::InvalidBucketPolicy { bucket, value }]
+            if bucket == "uploads" && value == "open"
+    ));
+}
+
+#[test]
+fn duplicate_bucket() {
+    assert!(matches!(
+        &errors(&[("GARAGE_BUCKETS", "uploads,static,uploads:public")])[..],
+        [ConfigError::DuplicateBucket { name }] if name == "uploads"
+    ));
+}
+
+#[test]
+fn invalid_bucket_names() {
+    for name in ["Uploads", "up", "-uploads", "up..loads", "192.168.0.1"] {
+        assert!(
+            matches!(
+                &errors(&[("GARAGE_BUCKETS", name)])[..],
+                [ConfigError::InvalidBucketName { name: invalid, .. }] if invalid == name
+            ),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn quoted_bucket_entries() {
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS",
+        r#"site:public:index_document="pages/home:v2.html":error_document="err,\"404\".html",uploads"#,
+    )]))
+    .unwrap();
+    assert_eq!(config.buckets.len(), 2);
+    let site = &config.buckets[0];
+    assert_eq!(site.name, "site");
+    assert_eq!(site.index_document.as_deref(), Some("pages/home:v2.html"));
+    assert_eq!(site.error_document.as_deref(), Some(r#"err,"404".html"#));
+    assert_eq!(config.buckets[1].name, "uploads");
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS",
+        r#"site:public:error_document="back\\slash.html""#,
+    )]))
+    .unwrap();
+    assert_eq!(
+        config.buckets[0].error_document.as_deref(),
+        Some(r"back\slash.html")
+    );
+    assert!(matches!(
+        errors(&[(
+            "GARAGE_BUCKETS",
+            r#"site:public:index_document="index.html"#
+        )])[..],
+        [ConfigError::InvalidBucketEntry { .. }]
+    ));
+}
+
+#[test]
+fn bucket_sizes() {
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS",
+        "one:max_size=512,two:max_size=100KiB,three:max_size=1 gib,four:max_size=2TiB",
+    )]))
+    .unwrap();
+    let sizes: Vec<_> = config
+        .buckets
+        .iter()
+        .map(|bucket| bucket.quotas.as_ref().unwrap().max_size)
+        .collect();
+    assert_eq!(
+        sizes,
+        [Some(512), Some(100 << 10), Some(1 << 30), Some(2 << 40)]
+    );
+    for size in ["1PB", "KiB", "-1", "99999999999TiB"] {
+        assert!(
+            matches!(
+                &errors(&[("GARAGE_BUCKETS", &format!("uploads:max_size={}", size))])[..],
+                [ConfigError::InvalidBucketOption { option, .. }] if option == "max_size"
+            ),
+            "{}",
+            size
+        );
+    }
+}
+
+#[test]
+fn buckets_json() {
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS_JSON",
+        r#"[
+            { "name": "media", "policy": "public", "quota": { "max_size": "1GiB", "max_objects": 10 } },
+            { "name": "logs", "quotas": { "max_size": 2048 } }
+        ]"#,
+    )]))
+    .unwrap();
+    // GARAGE_BUCKETS is ignored.
+    let names: Vec<_> = config.buckets.iter().map(|bucket| &bucket.name).collect();
+    assert_eq!(names, ["media", "logs"]);
+    assert!(matches!(config.buckets[0].policy, BucketPolicy::Public));
+    let quotas = config.buckets[0].quotas.as_ref().unwrap();
+    assert_eq!(
+        (quotas.max_size, quotas.max_objects),
+        (Some(1 << 30), Some(10))
+    );
+    assert!(matches!(config.buckets[1].policy, BucketPolicy::Private));
+    assert_eq!(
+        config.buckets[1].quotas.as_ref().unwrap().max_size,
+        Some(2048)
+    );
+    for json in [
+        r#"{ "name": "media" }"#,
+        r#"[{ "name": "media", "colour": "red" }]"#,
+        r#"[{ "name": "media", "quota": { "max_size": "lots" } }]"#,
+    ] {
+        assert!(
+            matches!(
+                errors(&[("GARAGE_BUCKETS_JSON", json)])[..],
+                [ConfigError::InvalidBucketsJson { .. }]
+            ),
+            "{}",
+            json
+        );
+    }
+}
+
+#[test]
+fn whitespace_is_trimmed() {
+    let config = Config::from_map(&vars(&[
+        ("GARAGE_ADMIN_TOKEN", "  admin-token\n"),
+        ("GARAGE_BUCKETS", " uploads , static : public "),
+    ]))
+    .unwrap();
+    assert_eq!(config.admin_token.expose(), "admin-token");
+    assert_eq!(config.buckets[0].name, "uploads");
+    assert_eq!(config.buckets[1].name, "static");
+    assert!(matches!(config.buckets[1].policy, BucketPolicy::Public));
+}
+
+#[test]
+fn errors_are_collected() {
+    let errors = errors(&[
+        ("GARAGE_BUCKETS", "uploads,uploads"),
+        ("GARAGE_START_TIMEOUT_SECS", "soon"),
+    ]);
+    assert_eq!(errors.len(), 2, "{:?}", errors);
+}
+
+#[test]
+fn zero_is_rejected() {
+    for name in [
+        "GARAGE_REPLICATION_FACTOR",
+        "GARAGE_START_LOG_INTERVAL_SECS",
+        "GARAGE_ADMIN_TIMEOUT_SECS",
+        "GARAGE_BUCKET_CONCURRENCY",
+        "GARAGE_START_POLL_INTERVAL_MS",
+    ] {
+        assert!(
+            matches!(
+                errors(&[(name, "0")])[..],
+                [ConfigError::NumberTooSmall {
+                    value: 0,
+                    min: 1,
+                    ..
+                }]
+            ),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn secret_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("admin_token");
+    let file = path.display().to_string();
+    std::fs::write(&path, "file-token\n").unwrap();
+    let config = Config::from_map(&vars(&[
+        ("GARAGE_ADMIN_TOKEN", ""),
+        ("GARAGE_ADMIN_TOKEN_FILE", &file),
+    ]))
+    .unwrap();
+    assert_eq!(config.admin_token.expose(), "file-token");
+    assert!(matches!(
+        errors(&[("GARAGE_ADMIN_TOKEN_FILE", &file)])[..],
+        [ConfigError::ConflictingVars {
+            name: "GARAGE_ADMIN_TOKEN"
+        }]
+    ));
+    std::fs::write(&path, b"\xff\xfe").unwrap();
+    assert!(matches!(
+        errors(&[
+            ("GARAGE_ADMIN_TOKEN", ""),
+            ("GARAGE_ADMIN_TOKEN_FILE", &file)
+        ])[..],
+        [ConfigError::InvalidUnicodeFile {
+            name: "GARAGE_ADMIN_TOKEN",
+            ..
+        }]
+    ));
+}
+
+#[test]
+fn builder() {
+    let config = Config::builder()
+        .admin_token("admin-token")
+        .metrics_token("metrics-token")
+        .access_key(ACCESS_KEY_ID, SECRET_ACCESS_KEY)
+        .add_bucket("uploads", BucketPolicy::Private)
+        .add_bucket("static", BucketPolicy::Public)
+        .var("GARAGE_ZONE", "eu-1")
+        .build()
+        .unwrap();
+    assert_eq!(config.access_key_id, ACCESS_KEY_ID);
+    assert_eq!(config.zone, "eu-1");
+    assert_eq!(config.buckets.len(), 2);
+    assert!(matches!(config.buckets[1].policy, BucketPolicy::Public));
+}
+
+#[test]
+fn builder_debug_redacts_secrets() {
+    let builder = Config::builder()
+        .admin_token("admin-token")
+        .access_key(ACCESS_KEY_ID, SECRET_ACCESS_KEY)
+        .var("GARAGE_ZONE", "eu-1");
+    let debug = format!("{:?}", builder);
+    assert!(!debug.contains("admin-token"), "{}", debug);
+    assert!(!debug.contains(SECRET_ACCESS_KEY), "{}", debug);
+    assert!(debug.contains(ACCESS_KEY_ID), "{}", debug);
+    assert!(debug.contains("\"GARAGE_ADMIN_TOKEN\": ***"), "{}", debug);
+}
+
+#[test]
+fn builder_validates_buckets() {
+    let result = Config::builder()
+        .admin_token("admin-token")
+        .metrics_token("metrics-token")
+        .add_bucket("uploads", BucketPolicy::Private)
+        .add_bucket("uploads", BucketPolicy::Public)
+        .build();
+    assert!(matches!(
+        result,
+        Err(ConfigError::DuplicateBucket { name }) if name == "uploads"
+    ));
+}
+
+#[test]
+fn remote_admin_url() {
+    assert!(errors(&[("GARAGE_ADMIN_URL", "https://garage.localhost:3443")]).is_empty());
+    assert!(errors(&[("GARAGE_ADMIN_URL", "http://[::1]:3903")]).is_empty());
+    assert!(matches!(
+        errors(&[("GARAGE_ADMIN_URL", "http://10.0.0.5:3903")])[..],
+        [ConfigError::RemoteAdminUrl { .. }]
+    ));
+    assert!(
+        errors(&[
+            ("GARAGE_ADMIN_URL", "https://10.0.0.5:3903"),
+            ("GARAGE_ADMIN_ALLOW_REMOTE", "true"),
+        ])
+        .is_empty()
+    );
+}
+
+#[test]
+fn bucket_mode() {
+    let config = Config::from_map(&vars(&[])).unwrap();
+    assert_eq!(config.bucket_mode, BucketMode::Reconcile);
+    let config = Config::from_map(&vars(&[("GARAGE_BUCKET_MODE", "create-only")])).unwrap();
+    assert_eq!(config.bucket_mode, BucketMode::CreateOnly);
+    assert_eq!(errors(&[("GARAGE_BUCKET_MODE", "create_only")]).len(), 1);
+}
+
+#[test]
+fn spa_bucket() {
+    let config = Config::from_map(&vars(&[(
+        "GARAGE_BUCKETS",
+        "app:public:spa=true,site:public",
+    )]))
+    .unwrap();
+    assert!(config.buckets[0].spa);
+    assert!(!config.buckets[1].spa);
+    assert!(matches!(
+        &errors(&[("GARAGE_BUCKETS", "app:public:spa=true:error_document=404.html")])[..],
+        [ConfigError::SpaWithErrorDocument { bucket }] if bucket == "app"
+    ));
+    assert!(matches!(
+        &errors(&[("GARAGE_BUCKETS", "app:public:spa=maybe")])[..],
+        [ConfigError::InvalidBucketOption { option, .. }] if option == "spa"
+    ));
+}
+
+#[test]
+fn s3_options_require_owner() {
+    let errors_for = |permissions: &str| {
+        errors(&[(
+            "GARAGE_BUCKETS_JSON",
+            &format!(
+                r#"[{{ "name": "uploads", "expire_after_days": 7, "cors": [], "permissions": {} }}]"#,
+                permissions
+            ),
+        )])
+    };
+    let local_alias = |key: &str| {
+        errors(&[(
+            "GARAGE_BUCKETS_JSON",
+            &format!(
+                r#"[{{ "name": "scratch", "cors": [], "local_alias": {{ "key": "{}" }} }}]"#,
+                key
+            ),
+        )])
+    };
+    assert!(errors_for(r#"[{ "owner": true, "read": true }]"#).is_empty());
+    assert!(
+        errors_for(&format!(
+            r#"[{{ "key": "{}", "owner": true }}]"#,
+            ACCESS_KEY_ID
+        ))
+        .is_empty()
+    );
+    let errors = errors_for(r#"[{ "read": true, "write": true }]"#);
+    assert!(
+        matches!(
+            &errors[..],
+            [
+                ConfigError::S3OptionWithoutOwner { option: "cors", .. },
+                ConfigError::S3OptionWithoutOwner {
+                    option: "expire_after_days",
+                    ..
+                },
+            ]
+        ),
+        "{:?}",
+        errors
+    );
+    assert!(local_alias(ACCESS_KEY_ID).is_empty());
+    assert!(matches!(
+        &local_alias("GKfedcba9876543210fedcba98")[..],
+        [ConfigError::S3OptionOnForeignLocalAlias { option: "cors", .. }]
+    ));
+}
+
+#[test]
+fn node_zone() {
+    let zone = |overrides: &[(&str, &str)]| Config::from_map(&vars(overrides)).unwrap().zone;
+    assert_eq!(zone(&[]), "dc1");
+    assert_eq!(zone(&[("GARAGE_ZONE", "eu-1")]), "eu-1");
+    assert_eq!(zone(&[("GARAGE_NODE_ZONE", "eu-2")]), "eu-2");
+    assert_eq!(
+        zone(&[("GARAGE_ZONE", "eu-1"), ("GARAGE_NODE_ZONE", "eu-2")]),
+        "eu-2"
+    );
+}
+
+#[test]
+fn extra_config_yields_to_set_variables() {
+    let dir = tempfile::tempdir().unwrap();
+    let extra = dir.path().join("extra.toml");
+    std::fs::write(
+        &extra,
+        "block_size = 4096\n\n[s3_api]\napi_bind_addr = \"0.0.0.0:1111\"\n",
+    )
+    .unwrap();
+    let rpc_secret = "0".repeat(64);
+    let render = |overrides: &[(&str, &str)]| {
+        let mut overrides = overrides.to_vec();
+        let config_path = dir.path().join("garage.toml").display().to_string();
+        let extra = extra.display().to_string();
+        overrides.extend([
+            ("GARAGE_CONFIG_PATH", config_path.as_str()),
+            ("GARAGE_CONFIG_EXTRA_PATH", extra.as_str()),
+            ("GARAGE_RPC_SECRET", rpc_secret.as_str()),
+        ]);
+        render_config(&Config::from_map(&vars(&overrides)).unwrap(), false)
+            .unwrap()
+            .unwrap()
+    };
+    let doc = render(&[]);
+    assert_eq!(doc["block_size"].as_integer(), Some(4096));
+    assert_eq!(
+        doc["s3_api"]["api_bind_addr"].as_str(),
+        Some("0.0.0.0:1111")
+    );
+    let doc = render(&[
+        ("GARAGE_BLOCK_SIZE", "1MiB"),
+        ("GARAGE_S3_API_BIND", "127.0.0.1:3900"),
+    ]);
+    assert_eq!(doc["block_size"].as_integer(), Some(1024 * 1024));
+    assert_eq!(
+        doc["s3_api"]["api_bind_addr"].as_str(),
+        Some("127.0.0.1:3900")
+    );
+}
+
+#[test]
+fn secret_files_are_private() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    let config = Config::from_map(&vars(&[
+        ("GARAGE_CONFIG_PATH", &path("garage.toml")),
+        ("GARAGE_RPC_SECRET_PATH", &path("rpc_secret")),
+    ]))
+    .unwrap();
+    create_config(&config).unwrap();
+    write_credentials(&config, dir.path().join("credentials.json").as_path()).unwrap();
+    for name in ["garage.toml", "rpc_secret", "credentials.json"] {
+        let mode = metadata(path(name)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600, "{}", name);
+    }
+}
+
+#[test]
+fn dry_run_leaves_config_untouched() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).display().to_string();
+    let config = Config::from_map(&vars(&[
+        ("GARAGE_CONFIG_PATH", &path("garage.toml")),
+        ("GARAGE_RPC_SECRET_PATH", &path("rpc_secret")),
+        ("GARAGE_DRY_RUN", "true"),
+    ]))
+    .unwrap();
+    create_config(&config).unwrap();
+    let dry_run_path = garage_config_path(&config);
+    assert!(dry_run_path.exists());
+    std::fs::remove_file(dry_run_path).unwrap();
+    assert!(!dir.path().join("garage.toml").exists());
+    assert!(!dir.path().join("rpc_secret").exists());
+}